@@ -1,8 +1,26 @@
-use clap::{Parser as ClapParser};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Parser as ClapParser, Subcommand};
+
+use crate::config::{AutoindexOverflow, EtagMode, FileConfig, RobotsPolicy, ServerConfig};
+use crate::logger::{LogColor, LogTarget};
+use crate::request::Method;
+
+// Environment variables read by `Cli::merge_env`
+pub const ENV_PORT: &str = "RUSTY_SERVER_PORT";
+pub const ENV_ROOT: &str = "RUSTY_SERVER_ROOT";
+pub const ENV_THREADS: &str = "RUSTY_SERVER_THREADS";
 
 #[derive(ClapParser, Default)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
+    /// Reads settings from a TOML file; flags given here override it
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
     /// Sets the root root (default 8080)
     #[arg(short, long, value_name = "DIRECTORY")]
     pub root: Option<String>,
@@ -10,4 +28,990 @@ pub struct Cli {
     /// Sets the port for the server to use (default static/)
     #[arg(short, long, value_name = "PORT")]
     pub port: Option<u16>,
+
+    /// IP address to listen on, e.g. 0.0.0.0 or :: for every interface (default 127.0.0.1)
+    #[arg(long, visible_alias = "address", value_name = "IP", value_parser = parse_host)]
+    pub host: Option<String>,
+
+    /// Retries binding this many times if the port is in use (default 0)
+    #[arg(long, value_name = "N")]
+    pub bind_retries: Option<u32>,
+
+    /// Delay between bind retries in milliseconds (default 500)
+    #[arg(long, value_name = "MS")]
+    pub bind_retry_delay: Option<u64>,
+
+    /// Header carrying a client-provided request ID (default X-Request-Id)
+    #[arg(long, value_name = "NAME")]
+    pub request_id_header: Option<String>,
+
+    /// Answers `/` with this status instead of serving files
+    #[arg(long, value_name = "CODE", value_parser = clap::value_parser!(u16).range(100..=599))]
+    pub root_status: Option<u16>,
+
+    /// Body sent with --root-status (default: the status reason)
+    #[arg(long, value_name = "TEXT", requires = "root_status")]
+    pub root_body: Option<String>,
+
+    /// Logs a warning for requests slower than this many milliseconds
+    #[arg(long, value_name = "MS")]
+    pub slow_request_threshold: Option<u64>,
+
+    /// Serves a custom page for a status, e.g. "404=/errors/404.html" (repeatable)
+    #[arg(long = "error-page", value_name = "CODE=PATH", value_parser = parse_error_page)]
+    pub error_pages: Vec<(u16, PathBuf)>,
+
+    /// Rejects requests with more header lines than this (default 100)
+    #[arg(long, value_name = "N")]
+    pub max_headers: Option<usize>,
+
+    /// Rejects requests whose line and headers exceed this many bytes (default 8192)
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1024..=1 << 20))]
+    pub max_header_size: Option<u64>,
+
+    /// Rejects request bodies larger than this many bytes with 413 (default 1048576)
+    #[arg(long, value_name = "BYTES")]
+    pub max_body_size: Option<u64>,
+
+    /// Where access log lines go: stdout, stderr or a file path (default stderr)
+    #[arg(long, value_name = "TARGET")]
+    pub access_log_target: Option<LogTarget>,
+
+    /// Where error log lines go: stdout, stderr or a file path (default stderr)
+    #[arg(long, value_name = "TARGET")]
+    pub error_log_target: Option<LogTarget>,
+
+    /// Colorizes log levels: auto (only on a terminal), always or never
+    #[arg(long = "color", value_name = "WHEN")]
+    pub log_color: Option<LogColor>,
+
+    /// Testing tool: sleeps this many milliseconds before every response
+    #[arg(long, value_name = "MS", hide = true)]
+    pub response_delay: Option<u64>,
+
+    /// Redirects requests for other hosts to this host (308)
+    #[arg(long, value_name = "HOST")]
+    pub canonical_host: Option<String>,
+
+    /// Honors incoming W3C traceparent headers, logging and echoing them
+    #[arg(long)]
+    pub trace_id_propagation: bool,
+
+    /// Starts a new traceparent when a request has none
+    #[arg(long, requires = "trace_id_propagation")]
+    pub generate_traceparent: bool,
+
+    /// Serves a built-in welcome page for / when the root has no index
+    #[arg(long)]
+    pub welcome: bool,
+
+    /// Serves the root index for missing paths without an extension, for single-page apps
+    #[arg(long)]
+    pub spa: bool,
+
+    /// Caps how many files are open at once; extra requests wait for a slot
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_open_files: Option<u64>,
+
+    /// Serves a generated robots.txt when the root has none
+    #[arg(long, value_name = "POLICY")]
+    pub default_robots: Option<RobotsPolicy>,
+
+    /// Answers a path with 451, e.g. "/banned.html=https://authority/notice" (repeatable)
+    #[arg(long = "legal-block", value_name = "PATH=URL", value_parser = parse_legal_block)]
+    pub legal_blocks: Vec<(String, String)>,
+
+    /// Enables TCP keepalive probes after this many idle seconds (default off)
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Lets a directory's .index file name the document served for it
+    #[arg(long)]
+    pub dot_index: bool,
+
+    /// Closes a connection after serving this many requests on it (default 100)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_requests_per_connection: Option<u64>,
+
+    /// Seconds an idle kept-alive connection waits for its next request (default 5)
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub keep_alive_timeout: Option<u64>,
+
+    /// Reads the root index at startup so the first request isn't slowed by a cold cache
+    #[arg(long)]
+    pub warmup: bool,
+
+    /// Refuses a method with 405, e.g. "OPTIONS" (repeatable)
+    #[arg(long = "deny-method", value_name = "METHOD", value_parser = parse_method)]
+    pub denied_methods: Vec<Method>,
+
+    /// Lists directory contents when a directory has no index file
+    #[arg(long)]
+    pub autoindex: bool,
+
+    /// Lists at most this many entries of a directory (default: no limit)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub autoindex_max_entries: Option<u64>,
+
+    /// For directories over --autoindex-max-entries: truncate the listing or forbid it with 403 (default truncate)
+    #[arg(long, value_name = "ACTION", requires = "autoindex_max_entries")]
+    pub autoindex_overflow: Option<AutoindexOverflow>,
+
+    /// Lets clients PUT and DELETE files under the root; anyone who can reach the server can write
+    #[arg(long)]
+    pub enable_upload: bool,
+
+    /// Lets DELETE remove a directory and everything in it (with --enable-upload)
+    #[arg(long)]
+    pub recursive_delete: bool,
+
+    /// Allows cross-origin requests from this origin, e.g. "https://app.example", or "*" for any (repeatable)
+    #[arg(long = "cors-origin", value_name = "ORIGIN", value_parser = parse_cors_origin)]
+    pub cors_origins: Vec<String>,
+
+    /// Requires HTTP Basic auth with these credentials; the config file's auth key keeps
+    /// them out of the process list
+    #[arg(long, value_name = "USER:PASS", value_parser = parse_auth)]
+    pub auth: Option<String>,
+
+    /// Realm named in the Basic auth challenge (default rusty_server)
+    #[arg(long, value_name = "REALM", value_parser = parse_auth_realm)]
+    pub auth_realm: Option<String>,
+
+    /// Answers clients making more than this many requests per second with 429 (default off)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+
+    /// Index file name tried for "/", in order given (repeatable, default index.html)
+    #[arg(long = "index", value_name = "NAME", value_parser = parse_index_name)]
+    pub index_files: Vec<String>,
+
+    /// Binds the first free port in a range such as 3000-3010, instead of --port
+    #[arg(long, value_name = "FIRST-LAST", value_parser = parse_port_range, conflicts_with = "port")]
+    pub port_range: Option<RangeInclusive<u16>>,
+
+    /// Switches to this user (name or uid) after binding; requires starting as root
+    #[arg(long, value_name = "USER")]
+    pub user: Option<String>,
+
+    /// Switches to this group (name or gid) after binding; defaults to the user's group
+    #[arg(long, value_name = "GROUP")]
+    pub group: Option<String>,
+
+    /// Sends Cache-Control: max-age=SECONDS with served files (default off)
+    #[arg(long, value_name = "SECONDS")]
+    pub cache_max_age: Option<u64>,
+
+    /// Overrides the max-age for one extension, e.g. "js=31536000" (repeatable)
+    #[arg(long = "cache-rule", value_name = "EXT=SECONDS", value_parser = parse_cache_rule)]
+    pub cache_rules: Vec<(String, u64)>,
+
+    /// Cache-Control for a content type or category, e.g. "image/*=max-age=604800" (repeatable);
+    /// replaces the default of no-cache for text/html and max-age=86400 for image/*
+    #[arg(long = "cache-type", value_name = "TYPE=VALUE", value_parser = parse_cache_type)]
+    pub cache_types: Vec<(String, String)>,
+
+    /// Derives ETags from mtime (size and modification time) or content (a checksum) (default mtime)
+    #[arg(long = "etag", value_name = "MODE")]
+    pub etag_mode: Option<EtagMode>,
+
+    /// Sends weak (W/) ETags; on by default for mtime ETags, off for content ones
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub etag_weak: Option<bool>,
+
+    /// Sets SO_RCVBUF on accepted connections, in bytes (default: OS choice)
+    #[arg(long = "so-rcvbuf", value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1024..=1 << 30))]
+    pub recv_buffer_size: Option<u64>,
+
+    /// Sets SO_SNDBUF on accepted connections, in bytes (default: OS choice)
+    #[arg(long = "so-sndbuf", value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1024..=1 << 30))]
+    pub send_buffer_size: Option<u64>,
+
+    /// Charset declared on text content types (default utf-8)
+    #[arg(long, value_name = "CHARSET")]
+    pub charset: Option<String>,
+
+    /// Seconds clients are told to wait via Retry-After on 503 responses (default 30)
+    #[arg(long, value_name = "SECONDS")]
+    pub retry_after: Option<u64>,
+
+    /// Worker threads serving connections (default: one per CPU)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=1024))]
+    pub threads: Option<u32>,
+
+    /// Connections that may wait for a free worker before more get a 503 (default 1024)
+    #[arg(long, value_name = "N")]
+    pub queue_capacity: Option<usize>,
+
+    /// Seconds a client gets to send a request's headers before a 408 (default 10)
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub request_timeout: Option<u64>,
+
+    /// Value of the Server response header (default rusty_server/<version>)
+    #[arg(long, value_name = "VALUE")]
+    pub server_header: Option<String>,
+
+    /// Sends no Server header
+    #[arg(long, conflicts_with = "server_header")]
+    pub no_server_header: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// One-off tasks run instead of the server.
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Writes .gz sidecars for compressible files under the root, then exits
+    Precompress {
+        /// Directory to walk (default: --root)
+        #[arg(value_name = "DIRECTORY")]
+        dir: Option<PathBuf>,
+    },
+}
+
+impl Cli {
+    /// Fills in the port, root and thread count from `RUSTY_SERVER_PORT`,
+    /// `RUSTY_SERVER_ROOT` and `RUSTY_SERVER_THREADS` when the flags are
+    /// absent. `lookup` reads a variable; an invalid value is an error.
+    pub fn merge_env(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        fn invalid(name: &str, value: &str) -> String {
+            format!("invalid {}={:?}", name, value)
+        }
+
+        if self.port.is_none()
+            && let Some(value) = lookup(ENV_PORT)
+        {
+            self.port = Some(value.trim().parse().map_err(|_| invalid(ENV_PORT, &value))?);
+        }
+        if self.root.is_none()
+            && let Some(value) = lookup(ENV_ROOT)
+        {
+            if value.is_empty() {
+                return Err(invalid(ENV_ROOT, &value));
+            }
+            self.root = Some(value);
+        }
+        if self.threads.is_none()
+            && let Some(value) = lookup(ENV_THREADS)
+        {
+            let threads = value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|n| (1..=1024).contains(n))
+                .ok_or_else(|| invalid(ENV_THREADS, &value))?;
+            self.threads = Some(threads);
+        }
+        Ok(())
+    }
+
+    /// Fills in settings not given on the command line from a config file,
+    /// checking file values the same way as the matching flags. Switches
+    /// turned on in the file can't be turned off by a flag.
+    pub fn merge_file(&mut self, file: FileConfig) -> Result<(), String> {
+        fn fill<T>(flag: &mut Option<T>, value: Option<T>) {
+            if flag.is_none() {
+                *flag = value;
+            }
+        }
+        fn positive(key: &str, value: Option<u64>) -> Result<Option<u64>, String> {
+            match value {
+                Some(0) => Err(format!("{} must be at least 1", key)),
+                _ => Ok(value),
+            }
+        }
+
+        fill(&mut self.host, file.host.as_deref().map(parse_host).transpose()?);
+        fill(&mut self.port, file.port);
+        fill(&mut self.root, file.root);
+        match file.threads {
+            Some(n) if !(1..=1024).contains(&n) => return Err(format!("threads must be 1-1024, got {}", n)),
+            threads => fill(&mut self.threads, threads),
+        }
+        fill(&mut self.queue_capacity, file.queue_capacity);
+        fill(&mut self.bind_retries, file.bind_retries);
+        fill(&mut self.bind_retry_delay, file.bind_retry_delay);
+        fill(&mut self.request_id_header, file.request_id_header);
+        match file.root_status {
+            Some(code) if !(100..=599).contains(&code) => {
+                return Err(format!("root-status must be 100-599, got {}", code));
+            }
+            status => fill(&mut self.root_status, status),
+        }
+        fill(&mut self.root_body, file.root_body);
+        if self.root_body.is_some() && self.root_status.is_none() {
+            return Err("root-body requires root-status".to_string());
+        }
+        fill(&mut self.slow_request_threshold, file.slow_request_threshold);
+        if self.legal_blocks.is_empty() {
+            self.legal_blocks = file
+                .legal_block
+                .iter()
+                .map(|block| parse_legal_block(block))
+                .collect::<Result<_, _>>()?;
+        }
+        if self.denied_methods.is_empty() {
+            self.denied_methods = file
+                .deny_method
+                .iter()
+                .map(|method| parse_method(method))
+                .collect::<Result<_, _>>()?;
+        }
+        self.trace_id_propagation |= file.trace_id_propagation.unwrap_or(false);
+        self.generate_traceparent |= file.generate_traceparent.unwrap_or(false);
+        if self.generate_traceparent && !self.trace_id_propagation {
+            return Err("generate-traceparent requires trace-id-propagation".to_string());
+        }
+        for (key, size) in [("so-rcvbuf", file.recv_buffer_size), ("so-sndbuf", file.send_buffer_size)] {
+            if let Some(n) = size
+                && !(1024..=1 << 30).contains(&n)
+            {
+                return Err(format!("{} must be 1024-1073741824, got {}", key, n));
+            }
+        }
+        fill(&mut self.recv_buffer_size, file.recv_buffer_size);
+        fill(&mut self.send_buffer_size, file.send_buffer_size);
+        if file.server_header.is_some() && file.no_server_header == Some(true) {
+            return Err("server-header and no-server-header can't both be set".to_string());
+        }
+        // A header named on the command line beats the file turning it off
+        if self.server_header.is_none() {
+            fill(&mut self.server_header, file.server_header);
+            self.no_server_header |= file.no_server_header.unwrap_or(false);
+        }
+        fill(&mut self.request_timeout, positive("request-timeout", file.request_timeout)?);
+        if self.index_files.is_empty() {
+            self.index_files = file.index.iter().map(|name| parse_index_name(name)).collect::<Result<_, _>>()?;
+        }
+        self.autoindex |= file.autoindex.unwrap_or(false);
+        fill(
+            &mut self.autoindex_max_entries,
+            positive("autoindex-max-entries", file.autoindex_max_entries)?,
+        );
+        fill(&mut self.autoindex_overflow, file.autoindex_overflow);
+        if self.autoindex_overflow.is_some() && self.autoindex_max_entries.is_none() {
+            return Err("autoindex-overflow requires autoindex-max-entries".to_string());
+        }
+        self.enable_upload |= file.enable_upload.unwrap_or(false);
+        self.recursive_delete |= file.recursive_delete.unwrap_or(false);
+        self.welcome |= file.welcome.unwrap_or(false);
+        self.spa |= file.spa.unwrap_or(false);
+        self.dot_index |= file.dot_index.unwrap_or(false);
+        self.warmup |= file.warmup.unwrap_or(false);
+        fill(&mut self.cache_max_age, file.cache_max_age);
+        if self.cache_rules.is_empty() {
+            self.cache_rules = file
+                .cache_rule
+                .iter()
+                .map(|(ext, seconds)| parse_cache_rule(&format!("{}={}", ext, seconds)))
+                .collect::<Result<_, _>>()?;
+        }
+        if self.cors_origins.is_empty() {
+            self.cors_origins = file
+                .cors_origin
+                .iter()
+                .map(|origin| parse_cors_origin(origin))
+                .collect::<Result<_, _>>()?;
+        }
+        if self.cache_types.is_empty() {
+            self.cache_types = file
+                .cache_type
+                .iter()
+                .map(|(content_type, value)| parse_cache_type(&format!("{}={}", content_type, value)))
+                .collect::<Result<_, _>>()?;
+        }
+        fill(&mut self.etag_mode, file.etag);
+        fill(&mut self.etag_weak, file.etag_weak);
+        if self.error_pages.is_empty() {
+            self.error_pages = file
+                .error_page
+                .iter()
+                .map(|(code, path)| parse_error_page(&format!("{}={}", code, path.display())))
+                .collect::<Result<_, _>>()?;
+        }
+        fill(&mut self.max_headers, file.max_headers);
+        fill(&mut self.max_body_size, file.max_body_size);
+        match file.max_header_size {
+            Some(n) if !(1024..=1 << 20).contains(&n) => {
+                return Err(format!("max-header-size must be 1024-1048576, got {}", n));
+            }
+            size => fill(&mut self.max_header_size, size.map(|n| n as u64)),
+        }
+        fill(
+            &mut self.max_requests_per_connection,
+            positive("max-requests-per-connection", file.max_requests_per_connection)?,
+        );
+        fill(&mut self.keep_alive_timeout, positive("keep-alive-timeout", file.keep_alive_timeout)?);
+        fill(&mut self.tcp_keepalive, positive("tcp-keepalive", file.tcp_keepalive)?);
+        fill(&mut self.max_open_files, positive("max-open-files", file.max_open_files)?);
+        fill(&mut self.charset, file.charset);
+        fill(&mut self.retry_after, file.retry_after);
+        fill(&mut self.access_log_target, file.access_log_target.as_deref().map(str::parse).transpose()?);
+        fill(&mut self.error_log_target, file.error_log_target.as_deref().map(str::parse).transpose()?);
+        fill(&mut self.canonical_host, file.canonical_host);
+        fill(&mut self.auth, file.auth.as_deref().map(parse_auth).transpose()?);
+        fill(&mut self.auth_realm, file.auth_realm.as_deref().map(parse_auth_realm).transpose()?);
+        match positive("rate-limit", file.rate_limit)? {
+            Some(n) if n > u64::from(u32::MAX) => return Err(format!("rate-limit is too large: {}", n)),
+            rate => fill(&mut self.rate_limit, rate.map(|n| n as u32)),
+        }
+        fill(&mut self.user, file.user);
+        fill(&mut self.group, file.group);
+        Ok(())
+    }
+
+    /// Builds the server configuration, using defaults for unset flags.
+    pub fn into_config(self) -> ServerConfig {
+        let defaults = ServerConfig::default();
+        ServerConfig {
+            host: self.host.unwrap_or(defaults.host),
+            port: self.port.unwrap_or(defaults.port),
+            root: self.root.unwrap_or(defaults.root),
+            bind_retries: self.bind_retries.unwrap_or(defaults.bind_retries),
+            bind_retry_delay: self
+                .bind_retry_delay
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.bind_retry_delay),
+            request_id_header: self.request_id_header.unwrap_or(defaults.request_id_header),
+            root_status: self.root_status,
+            root_body: self.root_body,
+            slow_request_threshold: self.slow_request_threshold.map(Duration::from_millis),
+            error_pages: self.error_pages.into_iter().collect(),
+            max_headers: self.max_headers.unwrap_or(defaults.max_headers),
+            max_header_bytes: self
+                .max_header_size
+                .map(|n| n as usize)
+                .unwrap_or(defaults.max_header_bytes),
+            max_body_size: self.max_body_size.unwrap_or(defaults.max_body_size),
+            access_log_target: self.access_log_target.unwrap_or(defaults.access_log_target),
+            error_log_target: self.error_log_target.unwrap_or(defaults.error_log_target),
+            log_color: self.log_color.unwrap_or(defaults.log_color),
+            response_delay: self.response_delay.map(Duration::from_millis),
+            canonical_host: self.canonical_host,
+            trace_propagation: self.trace_id_propagation,
+            generate_traceparent: self.generate_traceparent,
+            welcome: self.welcome,
+            spa: self.spa,
+            max_open_files: self.max_open_files.map(|n| n as usize),
+            default_robots: self.default_robots,
+            legal_blocks: self.legal_blocks,
+            tcp_keepalive: self.tcp_keepalive.map(Duration::from_secs),
+            dot_index: self.dot_index,
+            max_requests_per_connection: self
+                .max_requests_per_connection
+                .map(|n| n as usize)
+                .unwrap_or(defaults.max_requests_per_connection),
+            keep_alive_timeout: self
+                .keep_alive_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.keep_alive_timeout),
+            warmup: self.warmup,
+            denied_methods: self.denied_methods,
+            autoindex: self.autoindex,
+            autoindex_max_entries: self.autoindex_max_entries.map(|n| n as usize),
+            autoindex_overflow: self.autoindex_overflow.unwrap_or(defaults.autoindex_overflow),
+            enable_upload: self.enable_upload,
+            recursive_delete: self.recursive_delete,
+            cors_origins: self.cors_origins,
+            auth: self.auth,
+            auth_realm: self.auth_realm.unwrap_or(defaults.auth_realm),
+            rate_limit: self.rate_limit,
+            index_files: if self.index_files.is_empty() {
+                defaults.index_files
+            } else {
+                self.index_files
+            },
+            port_range: self.port_range,
+            user: self.user,
+            group: self.group,
+            cache_max_age: self.cache_max_age,
+            cache_rules: self.cache_rules.into_iter().collect(),
+            cache_types: if self.cache_types.is_empty() {
+                defaults.cache_types
+            } else {
+                self.cache_types.into_iter().collect()
+            },
+            etag_mode: self.etag_mode.unwrap_or(defaults.etag_mode),
+            etag_weak: self.etag_weak,
+            recv_buffer_size: self.recv_buffer_size.map(|n| n as usize),
+            send_buffer_size: self.send_buffer_size.map(|n| n as usize),
+            charset: self.charset.unwrap_or(defaults.charset),
+            retry_after: self.retry_after.unwrap_or(defaults.retry_after),
+            threads: self.threads.map(|n| n as usize).unwrap_or(defaults.threads),
+            queue_capacity: self.queue_capacity.unwrap_or(defaults.queue_capacity),
+            request_timeout: self
+                .request_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.request_timeout),
+            server_header: if self.no_server_header {
+                None
+            } else {
+                self.server_header.or(defaults.server_header)
+            },
+        }
+    }
+}
+
+fn parse_host(value: &str) -> Result<String, String> {
+    // IPv6 may be bracketed as in URLs, e.g. [::1]
+    let bare = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')).unwrap_or(value);
+    bare.parse::<IpAddr>()
+        .map(|_| value.to_string())
+        .map_err(|_| format!("not an IP address: {:?}", value))
+}
+
+fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let (first, last) = value
+        .split_once('-')
+        .ok_or_else(|| format!("expected FIRST-LAST, got {:?}", value))?;
+    let first: u16 = first.trim().parse().map_err(|_| format!("invalid port: {:?}", first))?;
+    let last: u16 = last.trim().parse().map_err(|_| format!("invalid port: {:?}", last))?;
+    if first == 0 || first > last {
+        return Err(format!("invalid port range: {:?}", value));
+    }
+    Ok(first..=last)
+}
+
+fn parse_index_name(value: &str) -> Result<String, String> {
+    // Only a plain file name in the root is allowed
+    if value.is_empty() || value.contains(['/', '\\']) || value == "." || value == ".." {
+        return Err(format!("index must be a plain file name: {:?}", value));
+    }
+    Ok(value.to_string())
+}
+
+fn parse_method(value: &str) -> Result<Method, String> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(format!("invalid method: {:?}", value));
+    }
+    Ok(Method::from(value.to_ascii_uppercase().as_str()))
+}
+
+fn parse_error_page(value: &str) -> Result<(u16, PathBuf), String> {
+    let (code, path) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected CODE=PATH, got '{}'", value))?;
+    let code: u16 = code
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid status code '{}'", code))?;
+    if !(400..=599).contains(&code) {
+        return Err(format!("status code {} is not an error status", code));
+    }
+    Ok((code, PathBuf::from(path.trim())))
+}
+
+fn parse_legal_block(value: &str) -> Result<(String, String), String> {
+    let (path, url) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected PATH=URL, got '{}'", value))?;
+    let (path, url) = (path.trim(), url.trim());
+    if !path.starts_with('/') {
+        return Err(format!("blocked path '{}' must start with '/'", path));
+    }
+    if url.is_empty() {
+        return Err("authority URL must not be empty".to_string());
+    }
+    Ok((path.to_string(), url.to_string()))
+}
+
+fn parse_auth(value: &str) -> Result<String, String> {
+    match value.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(value.to_string()),
+        _ => Err("expected USER:PASS with a non-empty user".to_string()),
+    }
+}
+
+fn parse_auth_realm(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+        return Err(format!("invalid realm '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+/// Accepts `*` or a `scheme://host[:port]` origin, as browsers send it.
+fn parse_cors_origin(value: &str) -> Result<String, String> {
+    let origin = value.trim().trim_end_matches('/');
+    if origin == "*" {
+        return Ok(origin.to_string());
+    }
+    match origin.split_once("://") {
+        Some((scheme, host)) if !scheme.is_empty() && !host.is_empty() && !host.contains('/') => {
+            Ok(origin.to_ascii_lowercase())
+        }
+        _ => Err(format!("expected an origin like 'https://example.com' or '*', got '{}'", value)),
+    }
+}
+
+fn parse_cache_type(value: &str) -> Result<(String, String), String> {
+    let (content_type, directives) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected TYPE=VALUE, got '{}'", value))?;
+    let content_type = content_type.trim().to_ascii_lowercase();
+    match content_type.split_once('/') {
+        Some((category, subtype)) if !category.is_empty() && !subtype.is_empty() => {}
+        _ => return Err(format!("expected a content type like 'image/png' or 'image/*', got '{}'", content_type)),
+    }
+    let directives = directives.trim();
+    if directives.is_empty() || directives.chars().any(char::is_control) {
+        return Err(format!("invalid Cache-Control value '{}'", directives));
+    }
+    Ok((content_type, directives.to_string()))
+}
+
+fn parse_cache_rule(value: &str) -> Result<(String, u64), String> {
+    let (ext, seconds) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXT=SECONDS, got '{}'", value))?;
+    let ext = ext.trim().trim_start_matches('.');
+    if ext.is_empty() {
+        return Err("extension must not be empty".to_string());
+    }
+    let seconds = seconds
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("invalid max-age '{}'", seconds))?;
+    Ok((ext.to_ascii_lowercase(), seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parses_error_page_mapping() {
+        let (code, path) = parse_error_page("404=/errors/404.html").unwrap();
+
+        assert_eq!(code, 404);
+        assert_eq!(path, PathBuf::from("/errors/404.html"));
+    }
+
+    #[test]
+    fn rejects_malformed_error_page_mapping() {
+        assert!(parse_error_page("/errors/404.html").is_err());
+        assert!(parse_error_page("abc=/errors/404.html").is_err());
+        assert!(parse_error_page("200=/ok.html").is_err());
+    }
+
+    #[test]
+    fn parses_legal_block_mapping() {
+        let (path, url) = parse_legal_block("/banned.html=https://authority.example/notice?id=1").unwrap();
+
+        assert_eq!(path, "/banned.html");
+        assert_eq!(url, "https://authority.example/notice?id=1");
+        assert!(parse_legal_block("banned.html=https://a").is_err());
+        assert!(parse_legal_block("/banned.html=").is_err());
+    }
+
+    #[test]
+    fn parses_deny_method_case_insensitively() {
+        assert_eq!(parse_method("head").unwrap(), Method::Head);
+        assert_eq!(parse_method("OPTIONS").unwrap(), Method::Options);
+        assert!(parse_method("").is_err());
+        assert!(parse_method("GE T").is_err());
+    }
+
+    #[test]
+    fn rejects_index_names_with_paths() {
+        assert_eq!(parse_index_name("home.html").unwrap(), "home.html");
+        assert!(parse_index_name("../secret.html").is_err());
+        assert!(parse_index_name("docs/index.html").is_err());
+        assert!(parse_index_name("").is_err());
+    }
+
+    #[test]
+    fn parses_host_addresses() {
+        assert_eq!(parse_host("0.0.0.0").unwrap(), "0.0.0.0");
+        assert_eq!(parse_host("192.168.1.10").unwrap(), "192.168.1.10");
+        assert!(parse_host("localhost").is_err());
+        assert!(parse_host("10.0.0.256").is_err());
+        assert!(parse_host("127.0.0.1:80").is_err());
+        assert_eq!(parse_host("::1").unwrap(), "::1");
+        assert_eq!(parse_host("[::]").unwrap(), "[::]");
+        assert!(parse_host("[::1").is_err());
+    }
+
+    #[test]
+    fn parses_port_range() {
+        assert_eq!(parse_port_range("3000-3010").unwrap(), 3000..=3010);
+        assert_eq!(parse_port_range("8080-8080").unwrap(), 8080..=8080);
+        assert!(parse_port_range("3010-3000").is_err());
+        assert!(parse_port_range("3000").is_err());
+        assert!(parse_port_range("0-10").is_err());
+        assert!(parse_port_range("3000-70000").is_err());
+    }
+
+    #[test]
+    fn parses_cache_rule() {
+        assert_eq!(parse_cache_rule("html=300").unwrap(), ("html".to_string(), 300));
+        assert_eq!(parse_cache_rule(".JS=31536000").unwrap(), ("js".to_string(), 31536000));
+        assert!(parse_cache_rule("html").is_err());
+        assert!(parse_cache_rule("=300").is_err());
+        assert!(parse_cache_rule("html=soon").is_err());
+    }
+
+    #[test]
+    fn parses_auth() {
+        assert_eq!(parse_auth("admin:s3cret:with:colons").unwrap(), "admin:s3cret:with:colons");
+        assert_eq!(parse_auth("admin:").unwrap(), "admin:");
+        assert!(parse_auth("admin").is_err());
+        assert!(parse_auth(":s3cret").is_err());
+        assert!(parse_auth_realm("Staff \"only\"").is_err());
+    }
+
+    #[test]
+    fn parses_cors_origin() {
+        assert_eq!(parse_cors_origin("*").unwrap(), "*");
+        assert_eq!(parse_cors_origin("https://App.Example/").unwrap(), "https://app.example");
+        assert_eq!(parse_cors_origin("http://localhost:3000").unwrap(), "http://localhost:3000");
+        assert!(parse_cors_origin("app.example").is_err());
+        assert!(parse_cors_origin("https://app.example/path").is_err());
+    }
+
+    #[test]
+    fn parses_cache_type() {
+        assert_eq!(
+            parse_cache_type("Image/*=max-age=604800, immutable").unwrap(),
+            ("image/*".to_string(), "max-age=604800, immutable".to_string())
+        );
+        assert!(parse_cache_type("text/html").is_err());
+        assert!(parse_cache_type("html=no-cache").is_err());
+        assert!(parse_cache_type("text/=no-cache").is_err());
+        assert!(parse_cache_type("text/html=").is_err());
+    }
+
+    #[test]
+    fn cache_type_flags_replace_the_default_policy() {
+        let config = Cli::default().into_config();
+        assert_eq!(config.cache_types.get("text/html").map(String::as_str), Some("no-cache"));
+
+        let cli = Cli::try_parse_from(["rusty_server", "--cache-type", "font/*=max-age=31536000"]).unwrap();
+        let config = cli.into_config();
+
+        assert_eq!(config.cache_types.len(), 1);
+        assert_eq!(config.cache_types.get("font/*").map(String::as_str), Some("max-age=31536000"));
+    }
+
+    #[test]
+    fn parses_etag_flags() {
+        let config = Cli::default().into_config();
+        assert_eq!((config.etag_mode, config.etag_weak), (EtagMode::Mtime, None));
+
+        let cli = Cli::try_parse_from(["rusty_server", "--etag", "content", "--etag-weak"]).unwrap();
+        let config = cli.into_config();
+        assert_eq!((config.etag_mode, config.etag_weak), (EtagMode::Content, Some(true)));
+
+        let cli = Cli::try_parse_from(["rusty_server", "--etag-weak=false"]).unwrap();
+        assert_eq!(cli.into_config().etag_weak, Some(false));
+    }
+
+    #[test]
+    fn parses_autoindex_limit_flags() {
+        let cli =
+            Cli::try_parse_from(["rusty_server", "--autoindex-max-entries", "500", "--autoindex-overflow", "forbid"])
+                .unwrap();
+        let config = cli.into_config();
+        assert_eq!(config.autoindex_max_entries, Some(500));
+        assert_eq!(config.autoindex_overflow, AutoindexOverflow::Forbid);
+
+        assert!(Cli::try_parse_from(["rusty_server", "--autoindex-max-entries", "0"]).is_err());
+        assert!(Cli::try_parse_from(["rusty_server", "--autoindex-overflow", "forbid"]).is_err());
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn env_vars_fill_in_unset_flags() {
+        let mut cli = Cli::try_parse_from(["rusty_server", "--root", "site"]).unwrap();
+
+        cli.merge_env(env(&[(ENV_PORT, "9000"), (ENV_ROOT, "/srv/www"), (ENV_THREADS, "6")]))
+            .unwrap();
+        let config = cli.into_config();
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.root, "site");
+        assert_eq!(config.threads, 6);
+    }
+
+    #[test]
+    fn env_vars_take_precedence_over_the_config_file() {
+        let mut cli = Cli::default();
+
+        cli.merge_env(env(&[(ENV_PORT, "9000")])).unwrap();
+        cli.merge_file(FileConfig::parse("port = 7000\nroot = \"/srv/site\"").unwrap()).unwrap();
+        let config = cli.into_config();
+
+        assert_eq!((config.port, config.root.as_str()), (9000, "/srv/site"));
+    }
+
+    #[test]
+    fn rejects_invalid_env_values() {
+        let err = Cli::default().merge_env(env(&[(ENV_PORT, "http")])).unwrap_err();
+        assert!(err.contains("RUSTY_SERVER_PORT"), "got: {}", err);
+        assert!(Cli::default().merge_env(env(&[(ENV_PORT, "70000")])).is_err());
+        assert!(Cli::default().merge_env(env(&[(ENV_THREADS, "0")])).is_err());
+        assert!(Cli::default().merge_env(env(&[(ENV_ROOT, "")])).is_err());
+    }
+
+    #[test]
+    fn config_file_fills_in_unset_flags() {
+        let file = FileConfig::parse(
+            r#"
+            port = 9000
+            root = "/srv/site"
+            threads = 8
+            index = ["home.html", "index.html"]
+            autoindex = true
+            autoindex-max-entries = 200
+            autoindex-overflow = "forbid"
+            max-headers = 50
+            max-header-size = 4096
+
+            [cache-rule]
+            JS = 31536000
+
+            [error-page]
+            404 = "/errors/404.html"
+            "#,
+        )
+        .unwrap();
+        let mut cli = Cli::try_parse_from(["rusty_server", "--port", "7000", "--config", "site.toml"]).unwrap();
+
+        cli.merge_file(file).unwrap();
+        let config = cli.into_config();
+
+        // The command line wins over the file
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.root, "/srv/site");
+        assert_eq!(config.threads, 8);
+        assert_eq!(config.index_files, vec!["home.html", "index.html"]);
+        assert!(config.autoindex);
+        assert_eq!(config.autoindex_max_entries, Some(200));
+        assert_eq!(config.autoindex_overflow, AutoindexOverflow::Forbid);
+        assert_eq!(config.max_headers, 50);
+        assert_eq!(config.max_header_bytes, 4096);
+        assert_eq!(config.cache_rules.get("js"), Some(&31536000));
+        assert_eq!(config.error_pages.get(&404), Some(&PathBuf::from("/errors/404.html")));
+        // Unset everywhere keeps the default
+        assert_eq!(config.charset, "utf-8");
+    }
+
+    #[test]
+    fn config_file_covers_server_and_header_settings() {
+        let file = FileConfig::parse(
+            r#"
+            bind-retries = 3
+            bind-retry-delay = 250
+            request-id-header = "X-Trace"
+            root-status = 503
+            root-body = "Back soon"
+            slow-request-threshold = 750
+            legal-block = ["/banned.html=https://authority.example/notice"]
+            deny-method = ["options", "HEAD"]
+            trace-id-propagation = true
+            generate-traceparent = true
+            so-rcvbuf = 65536
+            so-sndbuf = 131072
+            server-header = "edge"
+            request-timeout = 30
+            etag = "content"
+            etag-weak = true
+            "#,
+        )
+        .unwrap();
+        let mut cli = Cli::default();
+
+        cli.merge_file(file).unwrap();
+        let config = cli.into_config();
+
+        assert_eq!(config.bind_retries, 3);
+        assert_eq!(config.bind_retry_delay, Duration::from_millis(250));
+        assert_eq!(config.request_id_header, "X-Trace");
+        assert_eq!(config.root_status, Some(503));
+        assert_eq!(config.root_body.as_deref(), Some("Back soon"));
+        assert_eq!(config.slow_request_threshold, Some(Duration::from_millis(750)));
+        assert_eq!(
+            config.legal_blocks,
+            vec![("/banned.html".to_string(), "https://authority.example/notice".to_string())]
+        );
+        assert_eq!(config.denied_methods, vec![Method::Options, Method::Head]);
+        assert!(config.trace_propagation && config.generate_traceparent);
+        assert_eq!((config.recv_buffer_size, config.send_buffer_size), (Some(65536), Some(131072)));
+        assert_eq!(config.server_header.as_deref(), Some("edge"));
+        assert_eq!(config.request_timeout, Duration::from_secs(30));
+        assert_eq!((config.etag_mode, config.etag_weak), (EtagMode::Content, Some(true)));
+    }
+
+    #[test]
+    fn config_file_settings_are_validated_like_flags() {
+        for text in [
+            "root-status = 99",
+            "root-body = \"no status\"",
+            "legal-block = [\"banned.html=https://authority.example\"]",
+            "deny-method = [\"GET /\"]",
+            "generate-traceparent = true",
+            "so-rcvbuf = 10",
+            "request-timeout = 0",
+            "server-header = \"edge\"\nno-server-header = true",
+        ] {
+            let mut cli = Cli::default();
+            assert!(cli.merge_file(FileConfig::parse(text).unwrap()).is_err(), "accepted {:?}", text);
+        }
+
+        // The file turning the header off doesn't override one named on the command line
+        let mut cli = Cli::try_parse_from(["rusty_server", "--server-header", "cli"]).unwrap();
+        cli.merge_file(FileConfig::parse("no-server-header = true").unwrap()).unwrap();
+        assert_eq!(cli.into_config().server_header.as_deref(), Some("cli"));
+    }
+
+    #[test]
+    fn empty_config_file_keeps_defaults() {
+        let mut cli = Cli::default();
+        cli.merge_file(FileConfig::parse("").unwrap()).unwrap();
+
+        let config = cli.into_config();
+        let defaults = ServerConfig::default();
+        assert_eq!((config.port, config.root), (defaults.port, defaults.root));
+        assert_eq!(config.index_files, defaults.index_files);
+    }
+
+    #[test]
+    fn rejects_bad_config_file_values() {
+        assert!(FileConfig::parse("prot = 80").is_err());
+        assert!(FileConfig::parse("port = \"eighty\"").is_err());
+
+        let mut cli = Cli::default();
+        assert!(cli.merge_file(FileConfig::parse("index = [\"../x.html\"]").unwrap()).is_err());
+        let mut cli = Cli::default();
+        assert!(cli.merge_file(FileConfig::parse("threads = 0").unwrap()).is_err());
+        let mut cli = Cli::default();
+        assert!(cli.merge_file(FileConfig::parse("[error-page]\n200 = \"/ok.html\"").unwrap()).is_err());
+    }
+
+    #[test]
+    fn server_header_can_be_overridden_or_dropped() {
+        let config = Cli::try_parse_from(["rusty_server"]).unwrap().into_config();
+        assert_eq!(config.server_header.as_deref(), Some(crate::config::DEFAULT_SERVER_HEADER));
+
+        let config = Cli::try_parse_from(["rusty_server", "--server-header", "web"]).unwrap().into_config();
+        assert_eq!(config.server_header.as_deref(), Some("web"));
+
+        let config = Cli::try_parse_from(["rusty_server", "--no-server-header"]).unwrap().into_config();
+        assert_eq!(config.server_header, None);
+    }
+
+    #[test]
+    fn parses_precompress_subcommand() {
+        let cli = Cli::try_parse_from(["rusty_server", "--root", "site", "precompress"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Precompress { dir: None }));
+        assert_eq!(cli.root.as_deref(), Some("site"));
+
+        let cli = Cli::try_parse_from(["rusty_server", "precompress", "dist"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Precompress { dir: Some(PathBuf::from("dist")) }));
+    }
 }
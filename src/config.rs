@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::logger::{LogColor, LogTarget};
+use crate::request::{Method, DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEADER_BYTES};
+use crate::threadpool::default_threads;
+
+/// Product token sent in the `Server` header unless configured otherwise.
+pub const DEFAULT_SERVER_HEADER: &str = concat!("rusty_server/", env!("CARGO_PKG_VERSION"));
+
+/// What the generated default robots.txt tells crawlers.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum RobotsPolicy {
+    Allow,
+    Disallow,
+}
+
+/// What a file's ETag is derived from.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EtagMode {
+    /// Size and modification time, which is cheap but doesn't promise
+    /// byte-identical content.
+    Mtime,
+    /// A checksum of the file's bytes, read on every request.
+    Content,
+}
+
+/// What an autoindex does with a directory over `--autoindex-max-entries`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoindexOverflow {
+    /// List the first entries read, with a note that there are more.
+    Truncate,
+    /// Refuse the listing with 403.
+    Forbid,
+}
+
+/// Effective settings the server runs with.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// IP address to listen on.
+    pub host: String,
+    pub port: u16,
+    pub root: String,
+    /// How many times to retry binding when the address is in use.
+    pub bind_retries: u32,
+    pub bind_retry_delay: Duration,
+    /// Incoming header honored as the request ID, echoed on the response.
+    pub request_id_header: String,
+    /// Fixed status returned for `/` instead of serving files, for parked sites.
+    pub root_status: Option<u16>,
+    pub root_body: Option<String>,
+    /// Requests taking longer than this are logged at warn level.
+    pub slow_request_threshold: Option<Duration>,
+    /// Custom error pages by status code, relative to the root.
+    pub error_pages: HashMap<u16, PathBuf>,
+    pub max_headers: usize,
+    /// Cap on the bytes in a request's line and headers, answered with 431.
+    pub max_header_bytes: usize,
+    /// Largest request body read, by Content-Length; more is answered with 413.
+    pub max_body_size: u64,
+    pub access_log_target: LogTarget,
+    pub error_log_target: LogTarget,
+    /// Whether log level tags are colorized.
+    pub log_color: LogColor,
+    /// Artificial delay before every response, for exercising client timeouts.
+    pub response_delay: Option<Duration>,
+    /// Requests for any other Host are permanently redirected here.
+    pub canonical_host: Option<String>,
+    /// Honor and echo W3C `traceparent` headers.
+    pub trace_propagation: bool,
+    /// Start a new trace when a request carries no `traceparent`.
+    pub generate_traceparent: bool,
+    /// Serve a built-in welcome page for `/` when the root has no index.
+    pub welcome: bool,
+    /// Serve the root index for missing extensionless paths, for single-page
+    /// apps with client-side routing.
+    pub spa: bool,
+    /// Maximum number of files open at once while serving.
+    pub max_open_files: Option<usize>,
+    /// Generated robots.txt served when the root has none.
+    pub default_robots: Option<RobotsPolicy>,
+    /// Paths answered with 451, paired with the blocking authority's URL.
+    pub legal_blocks: Vec<(String, String)>,
+    /// Idle time before TCP keepalive probes start on accepted connections.
+    pub tcp_keepalive: Option<Duration>,
+    /// Let a directory's `.index` file name its index document.
+    pub dot_index: bool,
+    /// Requests served on one connection before it is closed.
+    pub max_requests_per_connection: usize,
+    /// How long an idle kept-alive connection waits for its next request.
+    pub keep_alive_timeout: Duration,
+    /// Read the root index at startup to warm the page cache.
+    pub warmup: bool,
+    /// Methods refused with 405 even though the server supports them.
+    pub denied_methods: Vec<Method>,
+    /// List a directory's entries when it has no index document.
+    pub autoindex: bool,
+    /// Most entries an autoindex lists; reading stops once a directory is
+    /// known to have more.
+    pub autoindex_max_entries: Option<usize>,
+    pub autoindex_overflow: AutoindexOverflow,
+    /// Index document names tried in order for `/`.
+    pub index_files: Vec<String>,
+    /// Ports tried in order instead of `port`; the first free one is used.
+    pub port_range: Option<RangeInclusive<u16>>,
+    /// User to run as once the listener is bound (Unix, started as root).
+    pub user: Option<String>,
+    /// Group to run as once the listener is bound; defaults to the user's.
+    pub group: Option<String>,
+    /// `Cache-Control: max-age` for served files without a more specific rule.
+    pub cache_max_age: Option<u64>,
+    /// `Cache-Control: max-age` by lowercase file extension.
+    pub cache_rules: HashMap<String, u64>,
+    /// `Cache-Control` value by content type, either exact (`text/html`) or
+    /// a whole category (`image/*`). Extension rules take precedence.
+    pub cache_types: HashMap<String, String>,
+    /// What served files' ETags are derived from.
+    pub etag_mode: EtagMode,
+    /// Send ETags as weak (`W/`) validators. `None` picks the mode's
+    /// default: weak for mtime, strong for content.
+    pub etag_weak: Option<bool>,
+    /// SO_RCVBUF for accepted connections, in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF for accepted connections, in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// Charset appended to text content types.
+    pub charset: String,
+    /// Seconds sent in `Retry-After` on every 503.
+    pub retry_after: u64,
+    /// Worker threads serving connections.
+    pub threads: usize,
+    /// Accepted connections that may wait for a free worker before new
+    /// ones are turned away with 503.
+    pub queue_capacity: usize,
+    /// `Server` header value, or `None` to send none.
+    pub server_header: Option<String>,
+    /// Longest a client may take to send a request's line and headers.
+    pub request_timeout: Duration,
+    /// Accept PUT uploads into, and DELETEs from, the root.
+    pub enable_upload: bool,
+    /// Let DELETE remove a directory along with everything in it.
+    pub recursive_delete: bool,
+    /// Origins allowed to make cross-origin requests, or `*` for any. Empty
+    /// disables CORS.
+    pub cors_origins: Vec<String>,
+    /// `user:pass` every request must present with HTTP Basic auth.
+    pub auth: Option<String>,
+    /// Realm named in the `WWW-Authenticate` challenge.
+    pub auth_realm: String,
+    /// Requests per second allowed from each client IPv4 address or IPv6
+    /// /64, with bursts of up to one second's worth; more are answered with 429.
+    pub rate_limit: Option<u32>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            root: "static".to_string(),
+            bind_retries: 0,
+            bind_retry_delay: Duration::from_millis(500),
+            request_id_header: "X-Request-Id".to_string(),
+            root_status: None,
+            root_body: None,
+            slow_request_threshold: None,
+            error_pages: HashMap::new(),
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            max_body_size: 1024 * 1024,
+            access_log_target: LogTarget::Stderr,
+            error_log_target: LogTarget::Stderr,
+            log_color: LogColor::Auto,
+            response_delay: None,
+            canonical_host: None,
+            trace_propagation: false,
+            generate_traceparent: false,
+            welcome: false,
+            spa: false,
+            max_open_files: None,
+            default_robots: None,
+            legal_blocks: Vec::new(),
+            tcp_keepalive: None,
+            dot_index: false,
+            max_requests_per_connection: 100,
+            keep_alive_timeout: Duration::from_secs(5),
+            warmup: false,
+            denied_methods: Vec::new(),
+            autoindex: false,
+            autoindex_max_entries: None,
+            autoindex_overflow: AutoindexOverflow::Truncate,
+            index_files: vec!["index.html".to_string()],
+            port_range: None,
+            user: None,
+            group: None,
+            cache_max_age: None,
+            cache_rules: HashMap::new(),
+            // Pages should pick up edits; images rarely change in place
+            cache_types: [("text/html", "no-cache"), ("image/*", "max-age=86400")]
+                .into_iter()
+                .map(|(content_type, value)| (content_type.to_string(), value.to_string()))
+                .collect(),
+            etag_mode: EtagMode::Mtime,
+            etag_weak: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            charset: "utf-8".to_string(),
+            retry_after: 30,
+            threads: default_threads(),
+            queue_capacity: 1024,
+            server_header: Some(DEFAULT_SERVER_HEADER.to_string()),
+            request_timeout: Duration::from_secs(10),
+            enable_upload: false,
+            recursive_delete: false,
+            cors_origins: Vec::new(),
+            auth: None,
+            auth_realm: "rusty_server".to_string(),
+            rate_limit: None,
+        }
+    }
+}
+
+/// Settings read from a `--config` TOML file. Keys are named after the
+/// command-line flags (`max-headers = 50`); every key is optional, so an
+/// empty file is valid, and flags given on the command line win.
+///
+/// `--port-range`, `--color`, `--default-robots` and the testing-only
+/// `--response-delay` have no key. MIME types can't be overridden, in the
+/// file or on the command line.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FileConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub root: Option<String>,
+    pub threads: Option<u32>,
+    pub queue_capacity: Option<usize>,
+    pub bind_retries: Option<u32>,
+    pub bind_retry_delay: Option<u64>,
+    pub request_id_header: Option<String>,
+    pub root_status: Option<u16>,
+    pub root_body: Option<String>,
+    pub slow_request_threshold: Option<u64>,
+    /// `PATH=URL` entries, like repeating `--legal-block`.
+    pub legal_block: Vec<String>,
+    /// Methods refused with 405, like repeating `--deny-method`.
+    pub deny_method: Vec<String>,
+    pub trace_id_propagation: Option<bool>,
+    pub generate_traceparent: Option<bool>,
+    #[serde(rename = "so-rcvbuf")]
+    pub recv_buffer_size: Option<u64>,
+    #[serde(rename = "so-sndbuf")]
+    pub send_buffer_size: Option<u64>,
+    pub server_header: Option<String>,
+    pub no_server_header: Option<bool>,
+    pub request_timeout: Option<u64>,
+    /// Index file names, like repeating `--index`.
+    pub index: Vec<String>,
+    pub autoindex: Option<bool>,
+    pub autoindex_max_entries: Option<u64>,
+    pub autoindex_overflow: Option<AutoindexOverflow>,
+    pub enable_upload: Option<bool>,
+    pub recursive_delete: Option<bool>,
+    /// Allowed CORS origins, like repeating `--cors-origin`.
+    pub cors_origin: Vec<String>,
+    pub auth: Option<String>,
+    pub auth_realm: Option<String>,
+    pub rate_limit: Option<u64>,
+    pub welcome: Option<bool>,
+    pub spa: Option<bool>,
+    pub dot_index: Option<bool>,
+    pub warmup: Option<bool>,
+    pub cache_max_age: Option<u64>,
+    /// Max-age by extension, like repeating `--cache-rule EXT=SECONDS`.
+    pub cache_rule: HashMap<String, u64>,
+    /// Cache-Control by content type, like repeating `--cache-type TYPE=VALUE`.
+    pub cache_type: HashMap<String, String>,
+    pub etag: Option<EtagMode>,
+    pub etag_weak: Option<bool>,
+    /// Pages by status code, like repeating `--error-page CODE=PATH`.
+    pub error_page: HashMap<String, PathBuf>,
+    pub max_headers: Option<usize>,
+    pub max_header_size: Option<usize>,
+    pub max_body_size: Option<u64>,
+    pub max_requests_per_connection: Option<u64>,
+    pub keep_alive_timeout: Option<u64>,
+    pub tcp_keepalive: Option<u64>,
+    pub max_open_files: Option<u64>,
+    pub charset: Option<String>,
+    pub retry_after: Option<u64>,
+    pub access_log_target: Option<String>,
+    pub error_log_target: Option<String>,
+    pub canonical_host: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> anyhow::Result<FileConfig> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        FileConfig::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<FileConfig, toml::de::Error> {
+        toml::from_str(text)
+    }
+}
@@ -1,7 +1,15 @@
 pub mod cli;
+pub mod config;
+pub mod logger;
+pub mod precompress;
+pub mod privileges;
+pub mod ratelimit;
 pub mod server;
 pub mod request;
 pub mod response;
+pub mod storage;
 pub mod threadpool;
+pub mod trace;
 
-pub use server::start_server;
+pub use config::ServerConfig;
+pub use server::{start_server, start_server_with_config, start_server_with_handle, ServerHandle};
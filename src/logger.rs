@@ -0,0 +1,224 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use env_logger::fmt::{Target, WriteStyle};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Log target used for access log lines (`info!(target: ACCESS_TARGET, ...)`).
+pub const ACCESS_TARGET: &str = "access";
+
+/// Where a stream of log lines is written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogTarget {
+    Stdout,
+    Stderr,
+    File(PathBuf),
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "stdout" => Ok(LogTarget::Stdout),
+            "stderr" => Ok(LogTarget::Stderr),
+            "" => Err("log target must not be empty".to_string()),
+            path => Ok(LogTarget::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl LogTarget {
+    fn to_env_target(&self) -> io::Result<Target> {
+        Ok(match self {
+            LogTarget::Stdout => Target::Stdout,
+            LogTarget::Stderr => Target::Stderr,
+            LogTarget::File(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Target::Pipe(Box::new(file))
+            }
+        })
+    }
+}
+
+/// Whether level tags are colorized.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum LogColor {
+    /// Colorize when writing to a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl LogColor {
+    fn write_style(self) -> WriteStyle {
+        match self {
+            LogColor::Auto => WriteStyle::Auto,
+            LogColor::Always => WriteStyle::Always,
+            LogColor::Never => WriteStyle::Never,
+        }
+    }
+}
+
+/// Routes access lines and errors to their own targets, and everything else
+/// through the usual `RUST_LOG`-filtered stderr logger.
+pub struct SplitLogger {
+    access: env_logger::Logger,
+    error: env_logger::Logger,
+    general: env_logger::Logger,
+}
+
+impl SplitLogger {
+    fn new(access: Target, error: Target, color: LogColor) -> SplitLogger {
+        let style = color.write_style();
+        SplitLogger {
+            access: env_logger::Builder::new()
+                .filter_level(LevelFilter::Info)
+                .target(access)
+                .write_style(style)
+                .build(),
+            error: env_logger::Builder::new()
+                .filter_level(LevelFilter::Error)
+                .target(error)
+                .write_style(style)
+                .build(),
+            general: env_logger::Builder::from_default_env().write_style(style).build(),
+        }
+    }
+
+    fn route(&self, metadata: &Metadata<'_>) -> &env_logger::Logger {
+        if metadata.target() == ACCESS_TARGET {
+            &self.access
+        } else if metadata.level() == Level::Error {
+            &self.error
+        } else {
+            &self.general
+        }
+    }
+}
+
+impl Log for SplitLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.route(metadata).enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.route(record.metadata()).log(record);
+    }
+
+    fn flush(&self) {
+        self.access.flush();
+        self.error.flush();
+        self.general.flush();
+    }
+}
+
+/// Installs the split logger as the global logger.
+pub fn init(access: &LogTarget, error: &LogTarget, color: LogColor) -> anyhow::Result<()> {
+    let logger = SplitLogger::new(access.to_env_target()?, error.to_env_target()?, color);
+    let max_level = logger
+        .general
+        .filter()
+        .max(logger.access.filter())
+        .max(logger.error.filter());
+
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// Writer that appends into a shared buffer so tests can inspect output.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn log_line(logger: &SplitLogger, level: Level, target: &str, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn parses_log_targets() {
+        assert_eq!("stdout".parse::<LogTarget>().unwrap(), LogTarget::Stdout);
+        assert_eq!("stderr".parse::<LogTarget>().unwrap(), LogTarget::Stderr);
+        assert_eq!(
+            "/var/log/rusty.log".parse::<LogTarget>().unwrap(),
+            LogTarget::File(PathBuf::from("/var/log/rusty.log"))
+        );
+        assert!("".parse::<LogTarget>().is_err());
+    }
+
+    #[test]
+    fn routes_access_and_error_lines_to_their_targets() {
+        let access = SharedBuffer::default();
+        let error = SharedBuffer::default();
+        let logger = SplitLogger::new(
+            Target::Pipe(Box::new(access.clone())),
+            Target::Pipe(Box::new(error.clone())),
+            LogColor::Never,
+        );
+
+        log_line(&logger, Level::Info, ACCESS_TARGET, "GET /index.html 200");
+        log_line(&logger, Level::Error, "rusty_server::server", "Connection error: boom");
+
+        let access = access.contents();
+        let error = error.contents();
+        assert!(access.contains("GET /index.html 200"), "access log: {}", access);
+        assert!(!access.contains("boom"), "access log: {}", access);
+        assert!(error.contains("Connection error: boom"), "error log: {}", error);
+        assert!(!error.contains("GET /index.html"), "error log: {}", error);
+    }
+
+    #[test]
+    fn colorizes_level_tags_only_when_asked() {
+        let colored = SharedBuffer::default();
+        let plain = SharedBuffer::default();
+        let always = SplitLogger::new(
+            Target::Pipe(Box::new(colored.clone())),
+            Target::Pipe(Box::new(colored.clone())),
+            LogColor::Always,
+        );
+        let never = SplitLogger::new(
+            Target::Pipe(Box::new(plain.clone())),
+            Target::Pipe(Box::new(plain.clone())),
+            LogColor::Never,
+        );
+
+        log_line(&always, Level::Error, "rusty_server::server", "boom");
+        log_line(&never, Level::Error, "rusty_server::server", "boom");
+
+        assert!(colored.contents().contains("\x1b["), "colored: {:?}", colored.contents());
+        assert!(!plain.contents().contains('\x1b'), "plain: {:?}", plain.contents());
+        assert!(plain.contents().contains("ERROR"));
+    }
+}
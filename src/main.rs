@@ -1,23 +1,40 @@
-use anyhow::Result;
+use std::env;
+
+use anyhow::{anyhow, Result};
 use clap::{Parser as ClapParser};
 use log::{info};
 
-use rusty_server::cli::{Cli};
-use rusty_server::start_server; // from lib.rs
+use rusty_server::cli::{Cli, Command};
+use rusty_server::config::FileConfig;
+use rusty_server::logger;
+use rusty_server::precompress::precompress;
+use rusty_server::start_server_with_config; // from lib.rs
 
 fn main() -> Result<()> {
-    env_logger::init();
-    info!("Rusty Server");
+    let mut cli = Cli::parse();
+    // Flags beat the environment, which beats the config file
+    cli.merge_env(|name| env::var(name).ok()).map_err(|e| anyhow!(e))?;
+    if let Some(path) = cli.config.clone() {
+        let file = FileConfig::load(&path)?;
+        cli.merge_file(file)
+            .map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))?;
+    }
+    let command = cli.command.take();
+    let config = cli.into_config();
+    logger::init(&config.access_log_target, &config.error_log_target, config.log_color)?;
 
-    let cli = Cli::parse();
+    if let Some(Command::Precompress { dir }) = command {
+        let dir = dir.unwrap_or_else(|| config.root.clone().into());
+        let report = precompress(&dir)?;
+        info!("Precompressed {}: {} written, {} up to date", dir.display(), report.written, report.up_to_date);
+        return Ok(());
+    }
 
-    let port = cli.port.unwrap_or(8080);
-    info!("port = {}", port);
-
-    let root = cli.root.as_deref().unwrap_or("static");
-    info!("root = {}", root);
+    info!("Rusty Server");
+    info!("port = {}", config.port);
+    info!("root = {}", config.root);
 
-    start_server(port, &root)
+    start_server_with_config(config)
 }
 
 #[cfg(test)]
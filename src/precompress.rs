@@ -0,0 +1,116 @@
+// src/precompress.rs
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::info;
+
+use crate::response::{detect_mime_type, is_compressible};
+
+/// What a precompress run did.
+#[derive(Debug, Default, PartialEq)]
+pub struct PrecompressReport {
+    /// Sidecars written or refreshed.
+    pub written: usize,
+    /// Compressible files whose sidecar was already current.
+    pub up_to_date: usize,
+}
+
+/// Walks `root` and writes a `.gz` sidecar next to every compressible file
+/// that lacks one or whose sidecar is older than the file, so they can be
+/// served precompressed.
+pub fn precompress(root: &Path) -> io::Result<PrecompressReport> {
+    let mut report = PrecompressReport::default();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else if needs_sidecar(&path) {
+                if sidecar_is_current(&path)? {
+                    report.up_to_date += 1;
+                } else {
+                    write_sidecar(&path)?;
+                    report.written += 1;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn needs_sidecar(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext != "gz") && is_compressible(detect_mime_type(path))
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+fn sidecar_is_current(path: &Path) -> io::Result<bool> {
+    let sidecar = match fs::metadata(sidecar_path(path)) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    Ok(sidecar.modified()? >= fs::metadata(path)?.modified()?)
+}
+
+fn write_sidecar(path: &Path) -> io::Result<()> {
+    let contents = fs::read(path)?;
+    let sidecar = sidecar_path(path);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(&sidecar)?), Compression::best());
+    encoder.write_all(&contents)?;
+    encoder.finish()?.flush()?;
+    info!("Wrote {}", sidecar.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn writes_sidecars_for_text_files_only() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("css")).unwrap();
+        fs::write(dir.path().join("index.html"), "<h1>Home</h1>").unwrap();
+        fs::write(dir.path().join("css/site.css"), "body { margin: 0 }").unwrap();
+        fs::write(dir.path().join("logo.png"), b"\x89PNG").unwrap();
+
+        let report = precompress(dir.path()).unwrap();
+
+        assert_eq!(report, PrecompressReport { written: 2, up_to_date: 0 });
+        assert!(dir.path().join("index.html.gz").exists());
+        assert!(dir.path().join("css/site.css.gz").exists());
+        assert!(!dir.path().join("logo.png.gz").exists());
+
+        // A second run finds everything current and doesn't compress sidecars
+        let report = precompress(dir.path()).unwrap();
+        assert_eq!(report, PrecompressReport { written: 0, up_to_date: 2 });
+        assert!(!dir.path().join("index.html.gz.gz").exists());
+    }
+
+    #[test]
+    fn sidecar_decompresses_to_the_original() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("app.js"), "console.log('hi');").unwrap();
+
+        precompress(dir.path()).unwrap();
+
+        let gz = fs::read(dir.path().join("app.js.gz")).unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(&gz[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "console.log('hi');");
+    }
+}
@@ -0,0 +1,104 @@
+// src/privileges.rs
+use anyhow::{bail, Result};
+
+/// Switches to `user` and/or `group` after the listener is bound, so a
+/// privileged port can be opened as root and served unprivileged. A user
+/// without a group also takes that user's primary group.
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    use log::info;
+    use nix::unistd::{setgid, setuid, Uid};
+
+    let flags = match (user, group) {
+        (None, None) => return Ok(()),
+        (Some(_), None) => "--user",
+        (None, Some(_)) => "--group",
+        (Some(_), Some(_)) => "--user and --group",
+    };
+    if !Uid::effective().is_root() {
+        bail!("{} requires starting the server as root", flags);
+    }
+
+    let (uid, gid) = unix::resolve_ids(user, group)?;
+    // Supplementary groups first, then the group, while we still may
+    #[cfg(not(any(target_vendor = "apple", target_os = "redox", target_os = "haiku")))]
+    nix::unistd::setgroups(&[gid])?;
+    #[cfg(any(target_vendor = "apple", target_os = "redox", target_os = "haiku"))]
+    log::warn!("Supplementary groups can't be dropped on this platform");
+    setgid(gid)?;
+    if let Some(uid) = uid {
+        setuid(uid)?;
+    }
+    info!("Dropped privileges to uid={} gid={}", Uid::current(), gid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    if user.is_some() || group.is_some() {
+        bail!("--user/--group are only supported on Unix");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+mod unix {
+    use anyhow::{anyhow, Result};
+    use nix::unistd::{Gid, Group, Uid, User};
+
+    /// Looks up the ids to switch to. Names may also be numeric ids.
+    pub(super) fn resolve_ids(user: Option<&str>, group: Option<&str>) -> Result<(Option<Uid>, Gid)> {
+        let user = user.map(lookup_user).transpose()?;
+        let gid = match (group, &user) {
+            (Some(group), _) => lookup_group(group)?,
+            (None, Some(user)) => user.gid,
+            (None, None) => unreachable!("caller checks that one is set"),
+        };
+        Ok((user.map(|user| user.uid), gid))
+    }
+
+    fn lookup_user(name: &str) -> Result<User> {
+        let found = match name.parse::<u32>() {
+            Ok(id) => User::from_uid(Uid::from_raw(id))?,
+            Err(_) => User::from_name(name)?,
+        };
+        found.ok_or_else(|| anyhow!("no such user: {}", name))
+    }
+
+    fn lookup_group(name: &str) -> Result<Gid> {
+        let found = match name.parse::<u32>() {
+            Ok(id) => Group::from_gid(Gid::from_raw(id))?,
+            Err(_) => Group::from_name(name)?,
+        };
+        found
+            .map(|group| group.gid)
+            .ok_or_else(|| anyhow!("no such group: {}", name))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn resolves_user_and_primary_group() {
+            let (uid, gid) = resolve_ids(Some("root"), None).unwrap();
+
+            assert_eq!(uid, Some(Uid::from_raw(0)));
+            assert_eq!(gid, Gid::from_raw(0));
+        }
+
+        #[test]
+        fn resolves_numeric_ids_and_explicit_group() {
+            let (uid, gid) = resolve_ids(Some("0"), Some("0")).unwrap();
+
+            assert_eq!(uid, Some(Uid::from_raw(0)));
+            assert_eq!(gid, Gid::from_raw(0));
+        }
+
+        #[test]
+        fn rejects_unknown_user() {
+            assert!(resolve_ids(Some("no-such-user-rusty"), None).is_err());
+            assert!(resolve_ids(None, Some("no-such-group-rusty")).is_err());
+        }
+    }
+}
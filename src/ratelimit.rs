@@ -0,0 +1,179 @@
+// src/ratelimit.rs
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Independently locked partitions of the client table, so workers checking
+/// different clients rarely wait on each other.
+const SHARDS: usize = 16;
+
+/// Most clients tracked at once. Past this the longest-tracked client is
+/// forgotten, which bounds memory however many addresses a flood uses.
+const MAX_CLIENTS: usize = 64 * 1024;
+
+/// Token-bucket limiter keyed by client address, shared by all workers.
+///
+/// Each client may burst up to one second's worth of requests, after which
+/// requests are allowed at the steady rate. IPv6 clients are limited per
+/// /64, the block a single host can usually draw addresses from.
+pub struct RateLimiter {
+    per_second: f64,
+    shard_capacity: usize,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+#[derive(Default)]
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Tracked clients, oldest first, for eviction.
+    order: VecDeque<IpAddr>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// # Panics
+    /// Panics if `per_second` is 0.
+    pub fn new(per_second: u32) -> RateLimiter {
+        RateLimiter::with_capacity(per_second, MAX_CLIENTS)
+    }
+
+    fn with_capacity(per_second: u32, max_clients: usize) -> RateLimiter {
+        assert!(per_second > 0);
+        RateLimiter {
+            per_second: f64::from(per_second),
+            shard_capacity: max_clients.div_ceil(SHARDS).max(1),
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    /// Takes a token for `ip`, or returns how long until one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let key = client_key(ip);
+        let shard = &self.shards[self.hasher.hash_one(key) as usize % SHARDS];
+        let mut shard = shard.lock().unwrap();
+        if !shard.buckets.contains_key(&key) {
+            if shard.buckets.len() >= self.shard_capacity
+                && let Some(oldest) = shard.order.pop_front()
+            {
+                shard.buckets.remove(&oldest);
+            }
+            shard.order.push_back(key);
+        }
+        let bucket = shard.buckets.entry(key).or_insert(Bucket {
+            tokens: self.per_second,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.per_second);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second))
+        }
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().buckets.len()).sum()
+    }
+}
+
+/// The address a client is limited under: IPv4 as is, IPv6 by its /64.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(v6.to_bits() & !u128::from(u64::MAX))),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn allows_a_burst_then_limits() {
+        let limiter = RateLimiter::new(3);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(CLIENT, now).is_ok());
+        }
+        let wait = limiter.check_at(CLIENT, now).unwrap_err();
+
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(334), "{:?}", wait);
+        // Other clients have their own bucket
+        assert!(limiter.check_at(OTHER, now).is_ok());
+    }
+
+    #[test]
+    fn refills_at_the_configured_rate() {
+        let limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        limiter.check_at(CLIENT, now).unwrap();
+        limiter.check_at(CLIENT, now).unwrap();
+
+        assert!(limiter.check_at(CLIENT, now + Duration::from_millis(400)).is_err());
+        assert!(limiter.check_at(CLIENT, now + Duration::from_millis(500)).is_ok());
+        // Idle time never banks more than one burst
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.check_at(CLIENT, later).is_ok());
+        assert!(limiter.check_at(CLIENT, later).is_ok());
+        assert!(limiter.check_at(CLIENT, later).is_err());
+    }
+
+    #[test]
+    fn limits_ipv6_clients_per_slash_64() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+        let first: IpAddr = "2001:db8:1:2::1".parse().unwrap();
+        let same_block: IpAddr = "2001:db8:1:2:dead:beef:0:7".parse().unwrap();
+        let next_block: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+
+        assert!(limiter.check_at(first, now).is_ok());
+        assert!(limiter.check_at(same_block, now).is_err());
+        assert!(limiter.check_at(next_block, now).is_ok());
+    }
+
+    #[test]
+    fn treats_ipv4_mapped_addresses_as_ipv4() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+
+        assert!(limiter.check_at(CLIENT, now).is_ok());
+        assert!(limiter.check_at("::ffff:192.0.2.1".parse().unwrap(), now).is_err());
+    }
+
+    #[test]
+    fn never_tracks_more_than_its_capacity() {
+        let limiter = RateLimiter::with_capacity(1, SHARDS * 4);
+        let now = Instant::now();
+
+        for n in 0..10_000u32 {
+            limiter.check_at(IpAddr::V4(Ipv4Addr::from(n)), now).unwrap();
+        }
+
+        assert!(limiter.tracked() <= SHARDS * 4, "tracked {}", limiter.tracked());
+        // The most recent client is still limited
+        assert!(limiter.check_at(IpAddr::V4(Ipv4Addr::from(9_999u32)), now).is_err());
+    }
+}
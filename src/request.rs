@@ -1,55 +1,396 @@
-use std::io::{self, Read};
-use std::net::TcpStream;
-use log::info;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Read};
 
+/// Default cap on the number of header lines in a request.
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+/// Default cap on the bytes in the request line and headers together.
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 8 * 1024;
+
+/// Request failures that should be answered with a specific status code.
 #[derive(Debug)]
+pub enum RequestError {
+    /// The request line isn't `METHOD TARGET [VERSION]`.
+    MalformedRequestLine,
+    /// Content-Length is over the configured body limit.
+    BodyTooLarge,
+    TooManyHeaders,
+    /// The request line and headers run past the byte limit.
+    HeadersTooLarge,
+    UnsupportedVersion(String),
+    /// Both Content-Length and Transfer-Encoding were sent, a request
+    /// smuggling vector (RFC 7230 section 3.3.3).
+    ConflictingFraming,
+    /// Content-Length isn't a decimal byte count.
+    InvalidContentLength,
+    /// An HTTP/1.1 request without the Host header RFC 7230 requires.
+    MissingHost,
+}
+
+impl RequestError {
+    pub fn status(&self) -> u16 {
+        match self {
+            RequestError::TooManyHeaders | RequestError::HeadersTooLarge => 431,
+            RequestError::BodyTooLarge => 413,
+            RequestError::UnsupportedVersion(_) => 505,
+            RequestError::MalformedRequestLine
+            | RequestError::ConflictingFraming
+            | RequestError::InvalidContentLength
+            | RequestError::MissingHost => 400,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::MalformedRequestLine => write!(f, "malformed request line"),
+            RequestError::BodyTooLarge => write!(f, "request body too large"),
+            RequestError::TooManyHeaders => write!(f, "too many request headers"),
+            RequestError::HeadersTooLarge => write!(f, "request headers too large"),
+            RequestError::UnsupportedVersion(version) => write!(f, "unsupported HTTP version '{}'", version),
+            RequestError::ConflictingFraming => {
+                write!(f, "request has both Content-Length and Transfer-Encoding")
+            }
+            RequestError::InvalidContentLength => write!(f, "invalid Content-Length"),
+            RequestError::MissingHost => write!(f, "HTTP/1.1 request without a Host header"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<RequestError> for io::Error {
+    fn from(err: RequestError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Status code to answer with for a failed request read/parse, if any.
+pub fn error_status(err: &io::Error) -> Option<u16> {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<RequestError>())
+        .map(RequestError::status)
+}
+
+/// HTTP request method.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Method {
+    #[default]
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    /// Any other token, kept verbatim.
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Other(token) => token,
+        }
+    }
+}
+
+impl From<&str> for Method {
+    /// Methods are case-sensitive, so only exact uppercase tokens match.
+    fn from(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            other => Method::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// HTTP protocol version from the request line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpVersion {
+    Http10,
+    #[default]
+    Http11,
+}
+
+impl HttpVersion {
+    /// Parses the request line's version token. A missing token means
+    /// HTTP/1.0, since some minimal clients omit it.
+    fn parse(token: Option<&str>) -> Result<HttpVersion, RequestError> {
+        match token {
+            None | Some("HTTP/1.0") => Ok(HttpVersion::Http10),
+            Some("HTTP/1.1") => Ok(HttpVersion::Http11),
+            Some(other) => Err(RequestError::UnsupportedVersion(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpVersion::Http10 => f.write_str("HTTP/1.0"),
+            HttpVersion::Http11 => f.write_str("HTTP/1.1"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct HttpRequest {
-    pub method: String,
+    pub method: Method,
     pub path: String,
+    pub version: HttpVersion,
+    /// Header values keyed by lowercased header name.
+    pub headers: HashMap<String, String>,
+    /// Everything after the first `?` in the request target, if any.
+    pub query: Option<String>,
+    /// The `Content-Length` body, once `read_body` has read it.
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Case-insensitive header lookup.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    /// Whether the client is waiting for `100 Continue` before sending a body.
+    pub fn expects_continue(&self) -> bool {
+        self.header("Expect")
+            .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Whether the client asked to upgrade to cleartext HTTP/2 (h2c).
+    pub fn is_h2c_upgrade(&self) -> bool {
+        let upgrade = self
+            .header("Upgrade")
+            .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("h2c")));
+        upgrade && self.header("HTTP2-Settings").is_some()
+    }
+
+    /// Checks rules that depend on the request as a whole, beyond what
+    /// parsing enforces: HTTP/1.1 requests must carry a Host header, and a
+    /// Content-Length must be a plain byte count.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.version == HttpVersion::Http11 && self.header("Host").is_none() {
+            return Err(RequestError::MissingHost.into());
+        }
+        if self.header("Content-Length").is_some() && self.content_length().is_none() {
+            return Err(RequestError::InvalidContentLength.into());
+        }
+        Ok(())
+    }
+
+    /// Refuses a declared body longer than `max_bytes`, before it's read.
+    pub fn check_body_size(&self, max_bytes: u64) -> io::Result<()> {
+        match self.content_length() {
+            Some(len) if len > max_bytes => Err(RequestError::BodyTooLarge.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The declared body length, if Content-Length is present and valid.
+    pub fn content_length(&self) -> Option<u64> {
+        let value = self.header("Content-Length")?.trim();
+        // Digits only: `parse` would also accept a leading `+`
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        value.parse().ok()
+    }
+
+    /// Whether the body uses Transfer-Encoding, which the server can't
+    /// skip over, so such a connection can't be reused.
+    pub fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding").is_some()
+    }
+
+    /// Whether the client wants the connection reused: the HTTP/1.1 default
+    /// unless it sent `Connection: close`, opt-in via `keep-alive` on HTTP/1.0.
+    pub fn wants_keep_alive(&self) -> bool {
+        let has_token = |wanted: &str| {
+            self.header("Connection")
+                .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case(wanted)))
+        };
+        match self.version {
+            HttpVersion::Http11 => !has_token("close"),
+            HttpVersion::Http10 => has_token("keep-alive"),
+        }
+    }
+
+    /// Whether a body follows the headers.
+    pub fn has_body(&self) -> bool {
+        self.header("Transfer-Encoding").is_some()
+            || self
+                .header("Content-Length")
+                .is_some_and(|value| value.trim() != "0")
+    }
+}
+
+/// Reads the `Content-Length` body following the headers into
+/// `request.body`, returning its length. Transfer-encoded bodies aren't
+/// read.
+pub fn read_body<R: BufRead>(reader: &mut R, request: &mut HttpRequest) -> io::Result<u64> {
+    let Some(len) = request.content_length().filter(|_| !request.is_chunked()) else {
+        return Ok(0);
+    };
+    let mut body = Vec::with_capacity(len as usize);
+    reader.take(len).read_to_end(&mut body)?;
+    if (body.len() as u64) < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-body"));
+    }
+    request.body = body;
+    Ok(len)
+}
+
+/// Decodes `%XX` escapes in a request path. Returns `None` for a truncated
+/// or non-hex escape, or if the decoded bytes aren't UTF-8.
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
 }
 
 pub fn parse_request(request_str: &str) -> std::io::Result<HttpRequest> {
-    if let Some(line) = request_str.lines().next() {
+    parse_request_with_limit(request_str, DEFAULT_MAX_HEADERS)
+}
+
+/// Parses a request, failing with `RequestError::TooManyHeaders` if it has
+/// more than `max_headers` header lines.
+pub fn parse_request_with_limit(request_str: &str, max_headers: usize) -> std::io::Result<HttpRequest> {
+    let mut lines = request_str.lines();
+    if let Some(line) = lines.next() {
         let parts: Vec<&str> = line.split_whitespace().collect();
         if parts.len() >= 2 {
-            let method = parts[0].to_string();
-            let path = parts[1].to_string();
-            return Ok(HttpRequest { method, path });
+            let method = Method::from(parts[0]);
+            let (path, query) = match parts[1].split_once('?') {
+                Some((path, query)) => (path.to_string(), Some(query.to_string())),
+                None => (parts[1].to_string(), None),
+            };
+            let version = HttpVersion::parse(parts.get(2).copied())?;
+            let headers = parse_headers(lines, max_headers)?;
+            if headers.contains_key("content-length") && headers.contains_key("transfer-encoding") {
+                return Err(RequestError::ConflictingFraming.into());
+            }
+            return Ok(HttpRequest {
+                method,
+                path,
+                version,
+                headers,
+                query,
+                body: Vec::new(),
+            });
+        }
+    }
+
+    Err(RequestError::MalformedRequestLine.into())
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>, max_headers: usize) -> Result<HashMap<String, String>, RequestError> {
+    let mut headers = HashMap::new();
+    for (count, line) in lines.enumerate() {
+        // Headers end at the first blank line
+        if line.is_empty() {
+            break;
+        }
+        if count >= max_headers {
+            return Err(RequestError::TooManyHeaders);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            // Repeated headers are equivalent to one comma-separated header
+            headers
+                .entry(name.trim().to_ascii_lowercase())
+                .and_modify(|existing: &mut String| {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
         }
     }
+    Ok(headers)
+}
+
+/// Reads the request line and headers, up to and including the blank line
+/// that ends them. Any bytes after it (e.g. a body) stay buffered in `reader`.
+pub fn read_request<R: BufRead>(reader: &mut R) -> std::io::Result<String> {
+    read_request_counted(reader).map(|(request_str, _)| request_str)
+}
 
-    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed request line"))
+/// Like `read_request`, also returning how many bytes were consumed,
+/// including any blank lines skipped before the request line.
+pub fn read_request_counted<R: BufRead>(reader: &mut R) -> std::io::Result<(String, usize)> {
+    read_request_limited(reader, usize::MAX)
 }
 
-pub fn read_request(stream: &mut TcpStream) -> std::io::Result<String> {
+/// Like `read_request_counted`, but gives up with `HeadersTooLarge` rather
+/// than buffer more than `max_bytes`.
+pub fn read_request_limited<R: BufRead>(reader: &mut R, max_bytes: usize) -> std::io::Result<(String, usize)> {
     let mut buffer = Vec::new();
-    let mut temp = [0; 512];
+    let mut line = Vec::new();
+    let mut consumed = 0;
 
-    // Read until we find "\r\n\r\n" (end of headers)
     loop {
-        let n = stream.read(&mut temp)?;
-        if n == 0 {
+        line.clear();
+        let remaining = (max_bytes - consumed) as u64;
+        let n = reader.by_ref().take(remaining).read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") && consumed + n == max_bytes {
+            return Err(RequestError::HeadersTooLarge.into());
+        }
+        if n == 0 || !line.ends_with(b"\n") {
             return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
         }
+        consumed += n;
 
-        buffer.extend_from_slice(&temp[..n]);
+        let blank = line == b"\r\n" || line == b"\n";
+        // Tolerate stray blank lines before the request line
+        if blank && buffer.is_empty() {
+            continue;
+        }
 
-        if buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+        buffer.extend_from_slice(&line);
+        if blank {
             break;
         }
     }
 
     let request_str = String::from_utf8_lossy(&buffer).to_string();
-    info!("request = {}", request_str);
-
-    Ok(request_str)
+    Ok((request_str, consumed))
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
+    use std::io::{BufReader, Read, Write};
     use std::net::{TcpListener, TcpStream};
     use std::thread;
 
@@ -58,19 +399,191 @@ mod tests {
         let request_str = "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n";
         let req = parse_request(request_str).unwrap();
 
-        assert_eq!(req.method, "GET");
+        assert_eq!(req.method, Method::Get);
         assert_eq!(req.path, "/index.html");
     }
 
+    #[test]
+    fn test_parse_request_splits_query() {
+        let req = parse_request("GET /style.css?v=3 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.path, "/style.css");
+        assert_eq!(req.query.as_deref(), Some("v=3"));
+
+        let req = parse_request("GET /search?q=a?b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.path, "/search");
+        assert_eq!(req.query.as_deref(), Some("q=a?b"));
+
+        let req = parse_request("GET /style.css HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.query, None);
+    }
+
     #[test]
     fn test_parse_request_root() {
         let request_str = "GET / HTTP/1.1\r\n\r\n";
         let req = parse_request(request_str).unwrap();
 
-        assert_eq!(req.method, "GET");
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.path, "/");
+    }
+
+    #[test]
+    fn test_parse_request_version() {
+        let req = parse_request("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.version, HttpVersion::Http11);
+
+        let req = parse_request("GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(req.version, HttpVersion::Http10);
+
+        // Minimal clients may omit the version entirely
+        let req = parse_request("GET /\r\n\r\n").unwrap();
+        assert_eq!(req.version, HttpVersion::Http10);
         assert_eq!(req.path, "/");
     }
 
+    #[test]
+    fn test_parse_request_unsupported_version() {
+        let err = parse_request("GET / HTTP/2.0\r\n\r\n").unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error_status(&err), Some(505));
+    }
+
+    #[test]
+    fn test_parse_request_rejects_conflicting_framing() {
+        let request_str = "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        let err = parse_request(request_str).unwrap_err();
+
+        assert_eq!(error_status(&err), Some(400));
+    }
+
+    #[test]
+    fn test_parse_request_headers() {
+        let request_str = "GET / HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: abc123\r\n\r\n";
+        let req = parse_request(request_str).unwrap();
+
+        assert_eq!(req.header("host"), Some("localhost"));
+        assert_eq!(req.header("X-REQUEST-ID"), Some("abc123"));
+        assert_eq!(req.header("accept"), None);
+    }
+
+    #[test]
+    fn test_parse_request_too_many_headers() {
+        let headers: String = (0..5).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect();
+        let request_str = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+
+        assert!(parse_request_with_limit(&request_str, 5).is_ok());
+
+        let err = parse_request_with_limit(&request_str, 4).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error_status(&err), Some(431));
+    }
+
+    #[test]
+    fn test_parse_request_header_map() {
+        let request_str = "GET /page HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Accept-Encoding: gzip, br\r\n\
+            If-Modified-Since: Wed, 21 Oct 2015 07:28:00 GMT\r\n\
+            Accept: text/html\r\n\
+            Accept: */*\r\n\
+            \r\n\
+            Not-A-Header: body text";
+        let req = parse_request(request_str).unwrap();
+
+        let mut expected = HashMap::new();
+        expected.insert("host".to_string(), "example.com".to_string());
+        expected.insert("accept-encoding".to_string(), "gzip, br".to_string());
+        expected.insert("if-modified-since".to_string(), "Wed, 21 Oct 2015 07:28:00 GMT".to_string());
+        expected.insert("accept".to_string(), "text/html, */*".to_string());
+        assert_eq!(req.headers, expected);
+        assert_eq!(req.header("If-Modified-Since"), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+    }
+
+    #[test]
+    fn test_wants_keep_alive_follows_version_and_connection_header() {
+        let req = parse_request("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(req.wants_keep_alive());
+        let req = parse_request("GET / HTTP/1.1\r\nConnection: Close\r\n\r\n").unwrap();
+        assert!(!req.wants_keep_alive());
+        let req = parse_request("GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert!(!req.wants_keep_alive());
+        let req = parse_request("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        assert!(req.wants_keep_alive());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("/my%20file.txt").as_deref(), Some("/my file.txt"));
+        assert_eq!(percent_decode("/caf%C3%A9").as_deref(), Some("/café"));
+        assert_eq!(percent_decode("/..%2f..%2Fetc").as_deref(), Some("/../../etc"));
+        assert_eq!(percent_decode("/plain").as_deref(), Some("/plain"));
+        assert_eq!(percent_decode("/bad%2"), None);
+        assert_eq!(percent_decode("/bad%zz"), None);
+        assert_eq!(percent_decode("/bad%+1"), None);
+        assert_eq!(percent_decode("/%ff"), None);
+    }
+
+    #[test]
+    fn test_validate_requires_host_on_http11_only() {
+        let req = parse_request("GET / HTTP/1.1\r\n\r\n").unwrap();
+        let err = req.validate().unwrap_err();
+        assert_eq!(error_status(&err), Some(400));
+
+        assert!(parse_request("GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap().validate().is_ok());
+        assert!(parse_request("GET / HTTP/1.0\r\n\r\n").unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn test_content_length_validation() {
+        let req = parse_request("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 12\r\n\r\n").unwrap();
+        assert_eq!(req.content_length(), Some(12));
+        assert!(req.validate().is_ok());
+
+        for bad in ["abc", "+5", "-1", ""] {
+            let req = parse_request(&format!("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n", bad)).unwrap();
+            assert_eq!(req.content_length(), None, "{:?}", bad);
+            assert_eq!(error_status(&req.validate().unwrap_err()), Some(400), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_has_body() {
+        assert!(!parse_request("GET / HTTP/1.1\r\n\r\n").unwrap().has_body());
+        assert!(!parse_request("POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n").unwrap().has_body());
+        assert!(parse_request("POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\n").unwrap().has_body());
+        assert!(parse_request("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap().has_body());
+    }
+
+    #[test]
+    fn test_parse_request_detects_h2c_upgrade() {
+        let request_str = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n";
+        let req = parse_request(request_str).unwrap();
+        assert!(req.is_h2c_upgrade());
+
+        let req = parse_request("GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n").unwrap();
+        assert!(!req.is_h2c_upgrade());
+    }
+
+    #[test]
+    fn test_parse_request_methods() {
+        let cases = [
+            ("HEAD", Method::Head),
+            ("POST", Method::Post),
+            ("PUT", Method::Put),
+            ("DELETE", Method::Delete),
+            ("OPTIONS", Method::Options),
+            ("PATCH", Method::Other("PATCH".to_string())),
+            ("get", Method::Other("get".to_string())),
+        ];
+
+        for (token, expected) in cases {
+            let req = parse_request(&format!("{} / HTTP/1.1\r\n\r\n", token)).unwrap();
+            assert_eq!(req.method, expected);
+            assert_eq!(req.method.to_string(), token);
+        }
+    }
+
     #[test]
     fn test_parse_request_malformed() {
         // Missing path
@@ -78,6 +591,7 @@ mod tests {
         let err = parse_request(request_str).unwrap_err();
 
         assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error_status(&err), Some(400));
     }
 
     #[test]
@@ -97,9 +611,8 @@ mod tests {
 
         // Spawn server thread to accept connection and run read_request
         let handle = thread::spawn(move || {
-            let (mut stream, _) = listener.accept().unwrap();
-            let result = read_request(&mut stream).unwrap();
-            result
+            let (stream, _) = listener.accept().unwrap();
+            read_request(&mut BufReader::new(stream)).unwrap()
         });
 
         // Connect as a client
@@ -128,8 +641,8 @@ mod tests {
 
         // Spawn a thread that will accept one connection and attempt to read
         let handle = thread::spawn(move || {
-            let (mut stream, _) = listener.accept().unwrap();
-            read_request(&mut stream)
+            let (stream, _) = listener.accept().unwrap();
+            read_request(&mut BufReader::new(stream))
         });
 
         // Connect as client and send an *incomplete* HTTP request (no \r\n\r\n)
@@ -158,8 +671,8 @@ mod tests {
 
         // Spawn the server thread to accept and read the request
         let handle = thread::spawn(move || {
-            let (mut stream, _) = listener.accept().unwrap();
-            read_request(&mut stream)
+            let (stream, _) = listener.accept().unwrap();
+            read_request(&mut BufReader::new(stream))
         });
 
         // Construct a long request line + many headers
@@ -185,4 +698,86 @@ mod tests {
         assert!(request_str.ends_with("\r\n\r\n"), "Should read until end of headers");
     }
 
+    #[test]
+    fn test_read_request_retains_bytes_after_headers() {
+        let headers: String = (0..50).map(|i| format!("X-Header-{}: {}\r\n", i, "v".repeat(40))).collect();
+        let raw = format!("POST /upload HTTP/1.1\r\n{}\r\nBODY BYTES", headers);
+        let mut reader = BufReader::with_capacity(64, raw.as_bytes());
+
+        let request_str = read_request(&mut reader).unwrap();
+        let request = parse_request(&request_str).unwrap();
+        assert_eq!(request.path, "/upload");
+        assert_eq!(request.headers.len(), 50);
+        assert_eq!(request.header("x-header-49"), Some("v".repeat(40).as_str()));
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "BODY BYTES");
+    }
+
+    #[test]
+    fn test_read_request_counted_includes_skipped_blank_lines() {
+        let raw = b"\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\nbody";
+        let mut reader = BufReader::new(&raw[..]);
+
+        let (request_str, consumed) = read_request_counted(&mut reader).unwrap();
+
+        assert_eq!(consumed, request_str.len() + 2);
+        assert_eq!(consumed, raw.len() - 4);
+    }
+
+    #[test]
+    fn test_read_body_reads_content_length_bytes() {
+        let raw = b"POST /form HTTP/1.1\r\nHost: a\r\nContent-Length: 11\r\n\r\nname=a&b=\x00\xffGET / HTTP/1.1\r\n\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+        let mut request = parse_request(&read_request(&mut reader).unwrap()).unwrap();
+
+        assert_eq!(read_body(&mut reader, &mut request).unwrap(), 11);
+
+        assert_eq!(request.body, b"name=a&b=\x00\xff");
+        // The next request is left for the next read
+        assert_eq!(read_request(&mut reader).unwrap(), "GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_read_body_errors_on_short_body() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort";
+        let mut reader = BufReader::new(&raw[..]);
+        let mut request = parse_request(&read_request(&mut reader).unwrap()).unwrap();
+
+        let err = read_body(&mut reader, &mut request).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_check_body_size() {
+        let request = parse_request("POST / HTTP/1.1\r\nContent-Length: 2048\r\n\r\n").unwrap();
+
+        assert!(request.check_body_size(2048).is_ok());
+        assert_eq!(error_status(&request.check_body_size(2047).unwrap_err()), Some(413));
+    }
+
+    #[test]
+    fn test_read_request_limited_stops_at_the_cap() {
+        let raw = format!("GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n", "a".repeat(100));
+        let mut reader = BufReader::new(raw.as_bytes());
+
+        let err = read_request_limited(&mut reader, 64).unwrap_err();
+        assert_eq!(error_status(&err), Some(431));
+
+        // A request exactly at the cap still fits
+        let mut reader = BufReader::new(raw.as_bytes());
+        let (request_str, _) = read_request_limited(&mut reader, raw.len()).unwrap();
+        assert_eq!(request_str, raw);
+    }
+
+    #[test]
+    fn test_read_request_skips_leading_blank_lines() {
+        let mut reader = BufReader::new(&b"\r\nGET / HTTP/1.1\r\n\r\n"[..]);
+
+        let request_str = read_request(&mut reader).unwrap();
+
+        assert_eq!(request_str, "GET / HTTP/1.1\r\n\r\n");
+    }
 }
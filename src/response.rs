@@ -1,213 +1,3242 @@
-use log::info;
-use std::fs;
-use std::io::{Write};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use flate2::{Compression, Crc, CrcWriter};
+use flate2::write::GzEncoder;
+use log::{error, info, warn};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{AutoindexOverflow, EtagMode, RobotsPolicy, ServerConfig};
+use crate::request::{percent_decode, HttpRequest, Method};
+use crate::storage::{FileMeta, FileReader, FileStore, OpenFile};
+
+/// A response ready to be written to the client.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub reason: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// A file copied to the client after the headers in place of `body`.
+    pub stream: Option<StreamedBody>,
+    /// Send the headers (including the body's Content-Length) but not the
+    /// body itself, as for HEAD.
+    pub omit_body: bool,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, reason: &'static str, body: Vec<u8>) -> HttpResponse {
+        HttpResponse {
+            status,
+            reason,
+            headers: Vec::new(),
+            body,
+            stream: None,
+            omit_body: false,
+        }
+    }
+
+    /// Length of the body to send, streamed or not.
+    pub fn body_len(&self) -> u64 {
+        match &self.stream {
+            Some(stream) => stream.len,
+            None => self.body.len() as u64,
+        }
+    }
+
+    pub fn add_header(&mut self, name: &str, value: impl Into<String>) {
+        self.headers.push((name.to_string(), value.into()));
+    }
+
+    /// Case-insensitive lookup of the first header named `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of an explicitly set `Content-Length` header, if any.
+    fn explicit_content_length(&self) -> Option<&str> {
+        self.header("Content-Length")
+    }
+
+    /// Serializes the status line and headers, adding `Content-Length` from
+    /// the body unless it was set explicitly.
+    fn head_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        // A 304 describes a body it doesn't send, so it gets no length of its own
+        if self.explicit_content_length().is_none() && self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body_len()));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    /// Serializes the status line, headers and body into raw HTTP. A
+    /// streamed body is left out.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.head_bytes();
+        if !self.omit_body {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+
+    /// Checks that the declared `Content-Length` matches `written` body bytes.
+    pub fn check_content_length(&self, written: usize) -> Result<(), String> {
+        let declared = match self.explicit_content_length() {
+            Some(value) => value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid Content-Length header '{}'", value))?,
+            None => self.body_len() as usize,
+        };
+        if declared != written {
+            return Err(format!(
+                "Content-Length mismatch on {} response: declared {} but wrote {} bytes",
+                self.status, declared, written
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn write_to<T: Write>(&mut self, mut stream: T) -> std::io::Result<()> {
+        stream.write_all(&self.head_bytes())?;
+        if self.omit_body {
+            return stream.flush();
+        }
+        let written = match &mut self.stream {
+            Some(body) => body.copy_to(&mut stream)? as usize,
+            None => {
+                stream.write_all(&self.body)?;
+                self.body.len()
+            }
+        };
+
+        if let Err(message) = self.check_content_length(written) {
+            error!("{}", message);
+            debug_assert!(false, "{}", message);
+        }
+
+        stream.flush()
+    }
+}
+
+/// A byte range of an open file, copied to the client in chunks rather than
+/// held in memory.
+pub struct StreamedBody {
+    reader: Box<dyn FileReader>,
+    offset: u64,
+    len: u64,
+}
+
+impl StreamedBody {
+    /// The `len` bytes of `reader` starting at `offset`.
+    pub fn new(reader: Box<dyn FileReader>, offset: u64, len: u64) -> StreamedBody {
+        StreamedBody { reader, offset, len }
+    }
+
+    fn copy_to<T: Write>(&mut self, stream: &mut T) -> io::Result<u64> {
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+        io::copy(&mut self.reader.by_ref().take(self.len), stream)
+    }
+}
+
+impl fmt::Debug for StreamedBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamedBody")
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Writes a 1xx interim response. These carry no headers or body of their
+/// own and must precede the final response.
+pub fn write_interim<T: Write>(mut stream: T, status: u16) -> std::io::Result<()> {
+    debug_assert!((100..200).contains(&status), "{} is not an interim status", status);
+    write!(stream, "HTTP/1.1 {} {}\r\n\r\n", status, reason_phrase(status))?;
+    stream.flush()
+}
+
+pub fn handle_response<T: Write>(stream: T, request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> std::io::Result<()> {
+    build_response(request, config, store).write_to(stream)
+}
+
+/// Builds the response to send for `request`.
+pub fn build_response(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> HttpResponse {
+    let mut response = resolve_response(request, config, store);
+    compress(request, &mut response);
+    add_cors_headers(request, &mut response, config);
+    add_charset(&mut response, &config.charset);
+    add_common_headers(&mut response, config);
+    // HEAD gets exactly the GET headers, without the body
+    if request.method == Method::Head {
+        response.omit_body = true;
+    }
+    response
+}
+
+/// Adds the headers every final response carries: `Date`, which RFC 9110
+/// asks of origin servers with a clock, and `Server` unless disabled.
+pub fn add_common_headers(response: &mut HttpResponse, config: &ServerConfig) {
+    if response.header("Date").is_none() {
+        response.add_header("Date", httpdate::fmt_http_date(SystemTime::now()));
+    }
+    if let Some(server) = &config.server_header
+        && response.header("Server").is_none()
+    {
+        response.add_header("Server", server.as_str());
+    }
+}
+
+/// Adds `token` to the response's single `Vary` header, which lists every
+/// request header the response depends on, once each.
+fn add_vary(response: &mut HttpResponse, token: &str) {
+    let existing = response
+        .headers
+        .iter_mut()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Vary"));
+    match existing {
+        Some((_, value)) => {
+            if !value.split(',').any(|listed| listed.trim().eq_ignore_ascii_case(token)) {
+                value.push_str(", ");
+                value.push_str(token);
+            }
+        }
+        None => response.add_header("Vary", token),
+    }
+}
+
+/// Bodies smaller than this aren't worth the gzip overhead.
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Gzips a full text-like body when the client accepts it. Partial content
+/// is left alone, since its byte offsets refer to the uncompressed file.
+///
+/// A client sending `Save-Data: on` gets small bodies compressed too, at the
+/// best level, trading server CPU for its bandwidth.
+fn compress(request: &HttpRequest, response: &mut HttpResponse) {
+    let save_data = saves_data(request);
+    if response.status != 200
+        || response.stream.is_some()
+        || response.header("Content-Encoding").is_some()
+        || (response.body.len() < MIN_COMPRESS_SIZE && !save_data)
+        || !response.header("Content-Type").is_some_and(is_compressible)
+    {
+        return;
+    }
+    add_vary(response, "Accept-Encoding");
+    if save_data {
+        add_vary(response, "Save-Data");
+    }
+    if !accepts_gzip(request) {
+        return;
+    }
+
+    let level = if save_data { Compression::best() } else { Compression::default() };
+    let mut encoder = GzEncoder::new(Vec::new(), level);
+    let compressed = encoder.write_all(&response.body).and_then(|()| encoder.finish());
+    match compressed {
+        // Tiny bodies can grow under gzip's framing; those go out as they are
+        Ok(body) if body.len() >= response.body.len() => {}
+        Ok(body) => {
+            response.body = body;
+            response.add_header("Content-Encoding", "gzip");
+            // The encoded bytes differ, so a strong validator no longer holds
+            for (name, value) in &mut response.headers {
+                if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                    value.insert_str(0, "W/");
+                }
+            }
+        }
+        Err(e) => error!("gzip failed, sending uncompressed: {}", e),
+    }
+}
+
+pub(crate) fn is_compressible(content_type: &str) -> bool {
+    is_text(content_type) || media_type(content_type) == "image/svg+xml"
+}
+
+/// Whether a content type is character data that a charset applies to.
+fn is_text(content_type: &str) -> bool {
+    let media_type = media_type(content_type);
+    media_type.starts_with("text/")
+        || matches!(media_type, "application/javascript" | "application/json" | "application/xml")
+}
+
+/// A content type without its parameters.
+fn media_type(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or("").trim()
+}
+
+/// Appends `; charset=...` to text content types that don't name one.
+fn add_charset(response: &mut HttpResponse, charset: &str) {
+    for (name, value) in &mut response.headers {
+        if name.eq_ignore_ascii_case("Content-Type") && is_text(value) && !value.contains("charset=") {
+            value.push_str("; charset=");
+            value.push_str(charset);
+        }
+    }
+}
+
+/// Whether `Accept-Encoding` lists gzip without refusing it via `q=0`.
+/// Whether the client asked to save data with the `Save-Data: on` hint.
+fn saves_data(request: &HttpRequest) -> bool {
+    request.header("Save-Data").is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+fn accepts_gzip(request: &HttpRequest) -> bool {
+    request.header("Accept-Encoding").is_some_and(|value| {
+        value.split(',').any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+    })
+}
+
+/// Resolves the request against the root.
+fn resolve_response(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> HttpResponse {
+    // Testing aid only: simulate a slow server
+    if let Some(delay) = config.response_delay {
+        thread::sleep(delay);
+    }
+
+    if let Some(response) = cors_preflight_response(request, config, store) {
+        return response;
+    }
+
+    if let Some(response) = unauthorized_response(request, config, store) {
+        return response;
+    }
+
+    let allowed = allowed_methods(config);
+    if !allowed.contains(&request.method) {
+        return method_not_allowed_response(request, &allowed);
+    }
+
+    if percent_decode(&request.path).is_none() {
+        return error_response(400, config, store);
+    }
+
+    match request.method {
+        Method::Put => return upload_response(request, config, store),
+        Method::Delete => return delete_response(request, config, store),
+        _ => {}
+    }
+
+    if let Some(response) = canonical_host_redirect(request, config) {
+        return response;
+    }
+
+    if let Some(response) = legal_block_response(request, config, store) {
+        return response;
+    }
+
+    if let (Some(status), "/") = (config.root_status, request.path.as_str()) {
+        return root_status_response(status, config);
+    }
+
+    let path = file_path(request, config, store);
+    info!("path = {}", path.display());
+
+    let content_type = detect_mime_type(&path);
+
+    // A precompressed sibling stands in for the file when the client takes gzip
+    let sibling = gzip_sibling(&path, store);
+    let source = match &sibling {
+        Some(gz) if accepts_gzip(request) => gz.as_path(),
+        _ => path.as_path(),
+    };
+    let encoding = Encoding {
+        negotiated: sibling.is_some(),
+        gzipped: source != path,
+    };
+
+    if escapes_root(request, source, config, store) {
+        warn!("Refusing {} outside the root", request.path);
+        return error_response(403, config, store);
+    }
+
+    let validators = Validators::of(store, source, config);
+    if validators.not_modified(request) {
+        let mut response = HttpResponse::new(304, reason_phrase(304), Vec::new());
+        response.add_header("Content-Type", content_type);
+        validators.add_to(&mut response);
+        encoding.add_to(&mut response);
+        add_cache_control(&mut response, &path, config);
+        return response;
+    }
+
+    match store.open(source).and_then(FileBody::load) {
+        Ok(contents) => {
+            let mut response = file_response(request, contents, content_type, config, store);
+            validators.add_to(&mut response);
+            encoding.add_to(&mut response);
+            if response.status != 416 {
+                add_cache_control(&mut response, &path, config);
+            }
+            response
+        }
+        Err(e) => read_error_response(&e, request, config, store),
+    }
+}
+
+/// Sets `Cache-Control` from the rule for the file's extension, else the
+/// policy for its content type, else the global max-age.
+fn add_cache_control(response: &mut HttpResponse, path: &Path, config: &ServerConfig) {
+    let rule = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| config.cache_rules.get(&ext.to_ascii_lowercase()));
+    let value = rule
+        .map(|max_age| format!("max-age={}", max_age))
+        .or_else(|| cache_type_policy(path, config).map(str::to_string))
+        .or_else(|| config.cache_max_age.map(|max_age| format!("max-age={}", max_age)));
+    if let Some(value) = value {
+        response.add_header("Cache-Control", value);
+    }
+}
+
+/// The configured `Cache-Control` for the file's content type, trying the
+/// exact type before its category wildcard.
+fn cache_type_policy<'a>(path: &Path, config: &'a ServerConfig) -> Option<&'a str> {
+    let content_type = detect_mime_type(path);
+    let category = content_type.split('/').next().unwrap_or_default();
+    config
+        .cache_types
+        .get(content_type)
+        .or_else(|| config.cache_types.get(&format!("{}/*", category)))
+        .map(String::as_str)
+}
+
+/// Stores the request body at the request path: 201 for a new file, 204 for
+/// a replaced one.
+fn upload_response(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> HttpResponse {
+    // Transfer-coded bodies aren't read, so storing one would store nothing
+    if request.is_chunked() {
+        return error_response(411, config, store);
+    }
+    let Some(path) = write_path(request, config) else {
+        return error_response(409, config, store);
+    };
+    if write_escapes_root(request, &path, config, store) {
+        warn!("Refusing upload to {} outside the root", request.path);
+        return error_response(403, config, store);
+    }
+    let existed = match store.metadata(&path) {
+        Ok(meta) if meta.is_dir => return error_response(409, config, store),
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    if let Err(e) = store.write(&path, &request.body) {
+        return write_error_response(&e, request, config, store);
+    }
+    info!("Stored {} bytes at {}", request.body.len(), path.display());
+    let status = if existed { 204 } else { 201 };
+    let mut response = HttpResponse::new(status, reason_phrase(status), Vec::new());
+    if status == 201 {
+        response.add_header("Location", request.path.as_str());
+    }
+    response
+}
+
+/// Removes the file at the request path: 204 once gone, 404 if it wasn't
+/// there. Directories are 409 unless recursive deletes are enabled.
+fn delete_response(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> HttpResponse {
+    let decoded = percent_decode(&request.path).unwrap_or_else(|| request.path.clone());
+    let path = Path::new(&config.root).join(decoded.trim_matches('/'));
+    if write_escapes_root(request, &path, config, store) || is_root(&path, config, store) {
+        warn!("Refusing to delete {}", request.path);
+        return error_response(403, config, store);
+    }
+    match store.metadata(&path) {
+        Ok(meta) if meta.is_dir && !config.recursive_delete => return error_response(409, config, store),
+        Ok(_) => {}
+        Err(e) => return read_error_response(&e, request, config, store),
+    }
+    if let Err(e) = store.remove(&path) {
+        return write_error_response(&e, request, config, store);
+    }
+    info!("Deleted {}", path.display());
+    HttpResponse::new(204, reason_phrase(204), Vec::new())
+}
+
+/// Whether `path` resolves to the root itself.
+fn is_root(path: &Path, config: &ServerConfig, store: &dyn FileStore) -> bool {
+    match (store.canonicalize(Path::new(&config.root)), store.canonicalize(path)) {
+        (Ok(root), Ok(path)) => path == root,
+        _ => false,
+    }
+}
+
+/// The file a write targets, or `None` when the path names a directory.
+/// Unlike reads, index documents aren't substituted.
+fn write_path(request: &HttpRequest, config: &ServerConfig) -> Option<PathBuf> {
+    let decoded = percent_decode(&request.path)?;
+    if decoded.ends_with(['/', '\\']) {
+        return None;
+    }
+    Some(Path::new(&config.root).join(decoded.trim_start_matches('/')))
+}
+
+/// Like [`escapes_root`], but for a path that may not exist yet: its nearest
+/// existing ancestor must resolve inside the root.
+fn write_escapes_root(request: &HttpRequest, path: &Path, config: &ServerConfig, store: &dyn FileStore) -> bool {
+    if escapes_root(request, path, config, store) {
+        return true;
+    }
+    let Ok(root) = store.canonicalize(Path::new(&config.root)) else {
+        return true;
+    };
+    path.ancestors()
+        .find_map(|ancestor| store.canonicalize(ancestor).ok())
+        .is_none_or(|existing| !existing.starts_with(root))
+}
+
+/// Maps a failed write to a response: 403 when it isn't permitted, 409 when
+/// part of the path is a file, anything else 500.
+fn write_error_response(e: &io::Error, request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> HttpResponse {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem | io::ErrorKind::Unsupported => {
+            warn!("Not permitted to write {}: {}", request.path, e);
+            error_response(403, config, store)
+        }
+        io::ErrorKind::NotADirectory | io::ErrorKind::AlreadyExists | io::ErrorKind::IsADirectory => {
+            error_response(409, config, store)
+        }
+        _ => {
+            error!("Failed to write {}: {}", request.path, e);
+            error_response(500, config, store)
+        }
+    }
+}
+
+/// Whether the request reaches outside the root, either with `..` segments
+/// (decoded first, so `%2e%2e` counts) or through a link.
+fn escapes_root(request: &HttpRequest, path: &Path, config: &ServerConfig, store: &dyn FileStore) -> bool {
+    let decoded = percent_decode(&request.path).unwrap_or_else(|| request.path.clone());
+    if decoded.split(['/', '\\']).any(|segment| segment == "..") {
+        return true;
+    }
+    // Missing files can't escape; they 404 like any other
+    match (store.canonicalize(Path::new(&config.root)), store.canonicalize(path)) {
+        (Ok(root), Ok(path)) => !path.starts_with(root),
+        _ => false,
+    }
+}
+
+/// `path` with `.gz` appended, if such a file exists and isn't older than
+/// `path` itself.
+fn gzip_sibling(path: &Path, store: &dyn FileStore) -> Option<PathBuf> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    let sibling = PathBuf::from(name);
+    let meta = store.metadata(&sibling).ok().filter(|meta| !meta.is_dir)?;
+    // An edited file outdates its sidecar until it's precompressed again
+    let source_modified = store.metadata(path).ok().and_then(|source| source.modified);
+    if let (Some(sibling_modified), Some(source_modified)) = (meta.modified, source_modified)
+        && sibling_modified < source_modified
+    {
+        return None;
+    }
+    Some(sibling)
+}
+
+/// Which encoding of a file was chosen, and whether there was a choice.
+struct Encoding {
+    /// A gzip sibling exists, so the response depends on `Accept-Encoding`.
+    negotiated: bool,
+    /// The body is the gzip sibling's bytes.
+    gzipped: bool,
+}
+
+impl Encoding {
+    fn add_to(&self, response: &mut HttpResponse) {
+        if self.negotiated {
+            add_vary(response, "Accept-Encoding");
+        }
+        // A 416 carries an error page, not the file
+        if self.gzipped && response.status != 416 {
+            response.add_header("Content-Encoding", "gzip");
+        }
+    }
+}
+
+/// Cache validators for a file, derived from its metadata or, in content
+/// ETag mode, its bytes.
+struct Validators {
+    modified: Option<SystemTime>,
+    etag: Option<String>,
+}
+
+impl Validators {
+    fn of(store: &dyn FileStore, path: &Path, config: &ServerConfig) -> Validators {
+        let meta = store.metadata(path).ok();
+        let modified = meta.as_ref().and_then(|meta| meta.modified);
+        let etag = match config.etag_mode {
+            EtagMode::Mtime => meta.zip(modified).map(|(meta, modified)| etag(meta.len, modified)),
+            EtagMode::Content => content_etag(store, path),
+        };
+        let weak = config.etag_weak.unwrap_or(config.etag_mode == EtagMode::Mtime);
+        Validators {
+            modified,
+            etag: etag.map(|etag| if weak { format!("W/{}", etag) } else { etag }),
+        }
+    }
+
+    /// Whether the client's cached copy is current. `If-None-Match` takes
+    /// precedence over `If-Modified-Since` when both are sent.
+    fn not_modified(&self, request: &HttpRequest) -> bool {
+        match request.header("If-None-Match") {
+            Some(tags) => self.etag.as_deref().is_some_and(|etag| etag_matches(tags, etag)),
+            None => self.modified.is_some_and(|modified| not_modified_since(request, modified)),
+        }
+    }
+
+    fn add_to(&self, response: &mut HttpResponse) {
+        if let Some(modified) = self.modified {
+            response.add_header("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+        if let Some(etag) = &self.etag {
+            response.add_header("ETag", etag.as_str());
+        }
+    }
+}
+
+/// ETag from size and mtime, so it survives restarts while the file is
+/// unchanged.
+fn etag(len: u64, modified: SystemTime) -> String {
+    let age = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("\"{:x}-{:x}.{:x}\"", len, age.as_secs(), age.subsec_nanos())
+}
+
+/// ETag from the size and CRC-32 of the file's bytes, which changes whenever
+/// the content does. `None` if the file can't be read.
+fn content_etag(store: &dyn FileStore, path: &Path) -> Option<String> {
+    let mut file = store.open(path).ok()?;
+    let mut crc = CrcWriter::new(io::sink());
+    io::copy(&mut file.reader, &mut crc).ok()?;
+    Some(format!("\"{:x}-{:08x}\"", file.len, crc.crc().sum()))
+}
+
+/// Whether an `If-None-Match` list names `etag`. The comparison is weak, as
+/// GET allows: a `W/` prefix on either side is ignored.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Whether the request's `If-Modified-Since` is no earlier than `modified`.
+/// HTTP dates have whole-second resolution, so the mtime is truncated to match.
+fn not_modified_since(request: &HttpRequest, modified: SystemTime) -> bool {
+    let Some(since) = request
+        .header("If-Modified-Since")
+        .and_then(|value| httpdate::parse_http_date(value.trim()).ok())
+    else {
+        return false;
+    };
+    let secs = modified.duration_since(UNIX_EPOCH).map_or(0, |age| age.as_secs());
+    since >= UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Files at least this large are streamed from the store rather than read
+/// into memory, and so are never gzipped on the fly.
+const STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+/// A file's contents: in memory when small, left open to stream otherwise.
+enum FileBody {
+    Buffered(Vec<u8>),
+    Streamed(OpenFile),
+}
+
+impl FileBody {
+    fn load(mut file: OpenFile) -> io::Result<FileBody> {
+        if file.len >= STREAM_THRESHOLD {
+            return Ok(FileBody::Streamed(file));
+        }
+        let mut contents = Vec::with_capacity(file.len as usize);
+        file.reader.read_to_end(&mut contents)?;
+        Ok(FileBody::Buffered(contents))
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            FileBody::Buffered(contents) => contents.len(),
+            FileBody::Streamed(file) => file.len as usize,
+        }
+    }
+
+    /// A response with `status` whose body is the bytes in `range`.
+    fn into_response(self, status: u16, range: Range<usize>) -> HttpResponse {
+        match self {
+            FileBody::Buffered(contents) if range.len() == contents.len() => {
+                HttpResponse::new(status, reason_phrase(status), contents)
+            }
+            FileBody::Buffered(contents) => HttpResponse::new(status, reason_phrase(status), contents[range].to_vec()),
+            FileBody::Streamed(file) => {
+                let mut response = HttpResponse::new(status, reason_phrase(status), Vec::new());
+                let body = StreamedBody::new(file.reader, range.start as u64, range.len() as u64);
+                response.stream = Some(body);
+                response
+            }
+        }
+    }
+}
+
+/// A file's contents as a 200, or as a 206/416 when the request has a `Range`.
+fn file_response(
+    request: &HttpRequest,
+    contents: FileBody,
+    content_type: &'static str,
+    config: &ServerConfig,
+    store: &dyn FileStore,
+) -> HttpResponse {
+    let len = contents.len();
+    // RFC 9110 defines Range for GET only; HEAD describes the full GET
+    let range = request.header("Range").filter(|_| request.method == Method::Get);
+    let mut response = match byte_range(range, len) {
+        ByteRange::Full => contents.into_response(200, 0..len),
+        ByteRange::Partial(start, end) => {
+            let mut response = contents.into_response(206, start..end + 1);
+            response.add_header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+            response
+        }
+        ByteRange::Unsatisfiable => {
+            // Close the file first: a store limiting open files may need its
+            // slot to read a custom error page
+            drop(contents);
+            let mut response = error_response(416, config, store);
+            response.add_header("Content-Range", format!("bytes */{}", len));
+            return response;
+        }
+    };
+    response.add_header("Content-Type", content_type);
+    response.add_header("Accept-Ranges", "bytes");
+    response
+}
+
+/// How a `Range` header applies to a body of a given length.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No usable range: serve everything.
+    Full,
+    /// Inclusive start and end offsets.
+    Partial(usize, usize),
+    /// A valid range lying entirely past the end.
+    Unsatisfiable,
+}
+
+/// Interprets a single `bytes=` range. Headers that can't be parsed, use
+/// another unit or ask for several ranges are ignored, as RFC 9110 allows.
+fn byte_range(header: Option<&str>, len: usize) -> ByteRange {
+    let Some(spec) = header.and_then(|value| value.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // Suffix range: the last `end` bytes
+        return match end.parse::<usize>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let Ok(start) = start.parse::<usize>() else {
+        return ByteRange::Full;
+    };
+    let end = if end.is_empty() {
+        usize::MAX
+    } else {
+        match end.parse::<usize>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end.min(len - 1))
+}
+
+/// The file a request maps to under the root, after `.index` resolution.
+fn file_path(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> PathBuf {
+    let root = config.root.as_str();
+    info!("root = {}", root);
+    let path = generate_path(request, root, &config.index_files, store);
+    if config.dot_index
+        && let Some(index) = dot_index_path(request, root, &path, store)
+    {
+        return index;
+    }
+    path
+}
+
+/// Reads the root index once so the first real request finds it in the page
+/// cache. Returns whether it was found; a missing index only logs a warning.
+pub fn warm_up(config: &ServerConfig, store: &dyn FileStore) -> bool {
+    let request = HttpRequest {
+        path: "/".to_string(),
+        ..Default::default()
+    };
+    let path = file_path(&request, config, store);
+    match store.read(&path) {
+        Ok(contents) => {
+            info!("Warmed up {} ({} bytes)", path.display(), contents.len());
+            true
+        }
+        Err(e) => {
+            warn!("Warmup could not read index {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+/// Maps a failed read to a response: a missing file (or a directory) is a
+/// 404 or built-in page, an unreadable one 403, anything else 500.
+fn read_error_response(e: &io::Error, request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> HttpResponse {
+    match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::IsADirectory | io::ErrorKind::NotADirectory => {
+            missing_file_response(request, config, store)
+        }
+        io::ErrorKind::PermissionDenied => {
+            warn!("Permission denied reading {}", request.path);
+            error_response(403, config, store)
+        }
+        _ => {
+            error!("Failed to read {}: {}", request.path, e);
+            error_response(500, config, store)
+        }
+    }
+}
+
+/// Response for a path with no file behind it: a built-in page or, in SPA
+/// mode, the root index if one applies, otherwise a 404.
+fn missing_file_response(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> HttpResponse {
+    if config.welcome && is_index_request(request) {
+        return welcome_response();
+    }
+    if let (Some(policy), "/robots.txt") = (config.default_robots, request.path.as_str()) {
+        return default_robots_response(policy);
+    }
+    if config.autoindex {
+        let path = generate_path(request, &config.root, &config.index_files, store);
+        if let Some(response) = requested_dir(request, &config.root, &path, store)
+            .and_then(|dir| directory_listing(request, &dir, config, store))
+        {
+            return response;
+        }
+    }
+    // Client-side routes have no file behind them, but assets do
+    if config.spa && !is_index_request(request) && Path::new(&request.path).extension().is_none() {
+        let index = HttpRequest {
+            method: request.method.clone(),
+            path: "/".to_string(),
+            version: request.version,
+            headers: request.headers.clone(),
+            ..Default::default()
+        };
+        return resolve_response(&index, config, store);
+    }
+    error_response(404, config, store)
+}
+
+/// HTML index of `dir`, linking each entry under the request path. It
+/// carries an ETag, so an unchanged directory revalidates with a 304.
+///
+/// Past `autoindex_max_entries` the listing is cut short or refused, and
+/// the rest of the directory is never read.
+fn directory_listing(
+    request: &HttpRequest,
+    dir: &Path,
+    config: &ServerConfig,
+    store: &dyn FileStore,
+) -> Option<HttpResponse> {
+    let mut names = match config.autoindex_max_entries {
+        // One name past the cap is enough to know there are more
+        Some(max) => store.read_dir_limited(dir, max.saturating_add(1)).ok()?,
+        None => store.read_dir(dir).ok()?,
+    };
+    let mut truncated_at = None;
+    if let Some(max) = config.autoindex_max_entries
+        && names.len() > max
+    {
+        if config.autoindex_overflow == AutoindexOverflow::Forbid {
+            warn!("Refusing to list {}: more than {} entries", request.path, max);
+            return Some(error_response(403, config, store));
+        }
+        warn!("Listing only {} entries of {}", max, request.path);
+        names.truncate(max);
+        truncated_at = Some(max);
+    }
+    names.sort();
+    let entries: Vec<(String, Option<FileMeta>)> = names
+        .into_iter()
+        .map(|name| {
+            let meta = store.metadata(&dir.join(&name)).ok();
+            (name, meta)
+        })
+        .collect();
+    let validators = Validators {
+        modified: None,
+        etag: Some(listing_etag(&request.path, &entries)),
+    };
+    if validators.not_modified(request) {
+        let mut response = HttpResponse::new(304, reason_phrase(304), Vec::new());
+        response.add_header("Content-Type", "text/html");
+        validators.add_to(&mut response);
+        return Some(response);
+    }
+
+    let base = if request.path.ends_with('/') {
+        request.path.clone()
+    } else {
+        format!("{}/", request.path)
+    };
+
+    let title = escape_html(&request.path);
+    let mut body = format!("<html><head><title>Index of {title}</title></head><body>\n<h1>Index of {title}</h1>\n<ul>\n");
+    for (name, meta) in &entries {
+        let is_dir = meta.as_ref().is_some_and(|meta| meta.is_dir);
+        let suffix = if is_dir { "/" } else { "" };
+        body.push_str(&format!(
+            "<li><a href=\"{href}{suffix}\">{label}{suffix}</a></li>\n",
+            href = escape_html(&format!("{}{}", base, percent_encode_segment(name))),
+            label = escape_html(name),
+        ));
+    }
+    body.push_str("</ul>\n");
+    if let Some(max) = truncated_at {
+        body.push_str(&format!("<p>Only {} entries are shown; this directory has more.</p>\n", max));
+    }
+    body.push_str("</body></html>\n");
+
+    let mut response = HttpResponse::new(200, reason_phrase(200), body.into_bytes());
+    response.add_header("Content-Type", "text/html");
+    validators.add_to(&mut response);
+    Some(response)
+}
+
+/// ETag for a directory listing, from the request path and each entry's
+/// name, kind and mtime: everything the page is built from.
+fn listing_etag(path: &str, entries: &[(String, Option<FileMeta>)]) -> String {
+    let mut crc = Crc::new();
+    crc.update(path.as_bytes());
+    for (name, meta) in entries {
+        let is_dir = meta.as_ref().is_some_and(|meta| meta.is_dir);
+        let age = meta
+            .as_ref()
+            .and_then(|meta| meta.modified)
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        crc.update(b"\0");
+        crc.update(name.as_bytes());
+        crc.update(&[u8::from(is_dir)]);
+        crc.update(&age.as_nanos().to_le_bytes());
+    }
+    format!("\"{:x}-{:08x}\"", entries.len(), crc.sum())
+}
+
+/// Percent-encodes a file name for use as one URL path segment, leaving
+/// only unreserved characters and sub-delimiters as they are.
+fn percent_encode_segment(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn default_robots_response(policy: RobotsPolicy) -> HttpResponse {
+    let body = match policy {
+        RobotsPolicy::Allow => "User-agent: *\nDisallow:\n",
+        RobotsPolicy::Disallow => "User-agent: *\nDisallow: /\n",
+    };
+    let mut response = HttpResponse::new(200, reason_phrase(200), body.as_bytes().to_vec());
+    response.add_header("Content-Type", "text/plain");
+    response
+}
+
+fn is_index_request(request: &HttpRequest) -> bool {
+    matches!(request.path.as_str(), "/" | "/index")
+}
+
+/// Built-in landing page served for `/` when the root has no index.
+fn welcome_response() -> HttpResponse {
+    let mut response = HttpResponse::new(200, reason_phrase(200), WELCOME_PAGE.to_vec());
+    response.add_header("Content-Type", "text/html");
+    response
+}
+
+const WELCOME_PAGE: &[u8] = b"<h1>Welcome to Rusty Server</h1>";
+
+/// Builds an error response, using the configured page for `status` if it
+/// can be read and a built-in body otherwise.
+pub fn error_response(status: u16, config: &ServerConfig, store: &dyn FileStore) -> HttpResponse {
+    let custom = config.error_pages.get(&status).and_then(|page| {
+        let mut path = PathBuf::from(&config.root);
+        path.push(page.strip_prefix("/").unwrap_or(page));
+        store.read(&path).ok().map(|body| (body, detect_mime_type(&path)))
+    });
+
+    let (body, content_type) =
+        custom.unwrap_or_else(|| (default_error_body(status, &config.root, store), "text/html"));
+
+    let mut response = HttpResponse::new(status, reason_phrase(status), body);
+    response.add_header("Content-Type", content_type);
+    add_retry_after(&mut response, config);
+    response
+}
+
+fn default_error_body(status: u16, root: &str, store: &dyn FileStore) -> Vec<u8> {
+    match status {
+        404 => handle_404(root, store),
+        _ => format!("<h1>{} {}</h1>", status, reason_phrase(status)).into_bytes(),
+    }
+}
+
+
+
+/// Methods the server knows how to answer.
+const ALLOWED_METHODS: &[Method] = &[Method::Get, Method::Head];
+
+/// The supported methods, plus PUT and DELETE when uploads are enabled,
+/// minus any denied by configuration.
+fn allowed_methods(config: &ServerConfig) -> Vec<Method> {
+    let uploads: &[Method] = if config.enable_upload { &[Method::Put, Method::Delete] } else { &[] };
+    ALLOWED_METHODS
+        .iter()
+        .chain(uploads)
+        .filter(|method| !config.denied_methods.contains(method))
+        .cloned()
+        .collect()
+}
+
+fn method_not_allowed_response(request: &HttpRequest, allowed: &[Method]) -> HttpResponse {
+    let allow = allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let body = format!(
+        "<h1>405 Method Not Allowed</h1><p>{} is not supported. Allowed methods: {}.</p>",
+        request.method, allow
+    );
+    let mut response = HttpResponse::new(405, reason_phrase(405), body.into_bytes());
+    response.add_header("Allow", allow);
+    response.add_header("Content-Type", "text/html");
+    response
+}
+
+/// A 401 challenge when credentials are required and the request's
+/// `Authorization` header doesn't carry them.
+fn unauthorized_response(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> Option<HttpResponse> {
+    let expected = config.auth.as_deref()?;
+    let presented = request.header("Authorization").and_then(basic_credentials);
+    if presented.is_some_and(|presented| constant_time_eq(presented.as_bytes(), expected.as_bytes())) {
+        return None;
+    }
+    let mut response = error_response(401, config, store);
+    response.add_header(
+        "WWW-Authenticate",
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", config.auth_realm),
+    );
+    Some(response)
+}
+
+/// The decoded `user:pass` of a `Basic` authorization header.
+fn basic_credentials(header: &str) -> Option<String> {
+    let (scheme, encoded) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = BASE64_STANDARD.decode(encoded.trim()).ok()?;
+    String::from_utf8(decoded).ok()
+}
+
+/// Compares without returning early, so the time taken doesn't reveal how
+/// much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for (i, &expected) in b.iter().enumerate() {
+        diff |= usize::from(a.get(i).copied().unwrap_or(!expected) ^ expected);
+    }
+    diff == 0
+}
+
+/// The `Access-Control-Allow-Origin` value for the request's `Origin`, if
+/// that origin is allowed.
+fn cors_allow_origin<'a>(request: &'a HttpRequest, config: &ServerConfig) -> Option<&'a str> {
+    let origin = request.header("Origin")?;
+    if config.cors_origins.iter().any(|allowed| allowed == "*") {
+        return Some("*");
+    }
+    config
+        .cors_origins
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        .then_some(origin)
+}
+
+/// Lets an allowed origin read the response. Unless any origin is allowed,
+/// the answer depends on `Origin`, so caches are told to vary on it.
+fn add_cors_headers(request: &HttpRequest, response: &mut HttpResponse, config: &ServerConfig) {
+    if config.cors_origins.is_empty() {
+        return;
+    }
+    if response.header("Access-Control-Allow-Origin").is_none()
+        && let Some(origin) = cors_allow_origin(request, config)
+    {
+        response.add_header("Access-Control-Allow-Origin", origin);
+    }
+    if !config.cors_origins.iter().any(|allowed| allowed == "*") {
+        add_vary(response, "Origin");
+    }
+}
+
+/// Answers a CORS preflight, the OPTIONS request a browser sends to ask
+/// whether a cross-origin request may follow, with 204 for allowed origins.
+fn cors_preflight_response(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> Option<HttpResponse> {
+    if config.cors_origins.is_empty()
+        || request.method != Method::Options
+        || request.header("Access-Control-Request-Method").is_none()
+    {
+        return None;
+    }
+    if cors_allow_origin(request, config).is_none() {
+        warn!("Refusing CORS preflight from {}", request.header("Origin").unwrap_or("no origin"));
+        return Some(error_response(403, config, store));
+    }
+    let methods = allowed_methods(config)
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut response = HttpResponse::new(204, reason_phrase(204), Vec::new());
+    response.add_header("Access-Control-Allow-Methods", methods);
+    if let Some(headers) = request.header("Access-Control-Request-Headers") {
+        response.add_header("Access-Control-Allow-Headers", headers);
+    }
+    response.add_header("Access-Control-Max-Age", "600");
+    Some(response)
+}
+
+/// Redirects requests whose Host differs from the configured canonical host.
+fn canonical_host_redirect(request: &HttpRequest, config: &ServerConfig) -> Option<HttpResponse> {
+    let canonical = config.canonical_host.as_deref()?;
+    let host = request.header("Host")?;
+    if host.eq_ignore_ascii_case(canonical) {
+        return None;
+    }
+
+    let query = request.query.as_deref().map(|query| format!("?{}", query)).unwrap_or_default();
+    let location = format!("http://{}{}{}", canonical, request.path, query);
+    let body = format!("<h1>308 Permanent Redirect</h1><a href=\"{0}\">{0}</a>", escape_html(&location));
+    let mut response = HttpResponse::new(308, reason_phrase(308), body.into_bytes());
+    response.add_header("Location", location);
+    response.add_header("Content-Type", "text/html");
+    Some(response)
+}
+
+/// 451 for paths blocked on legal grounds, naming the blocking authority.
+fn legal_block_response(request: &HttpRequest, config: &ServerConfig, store: &dyn FileStore) -> Option<HttpResponse> {
+    if config.legal_blocks.is_empty() {
+        return None;
+    }
+    let path = normalized_path(request);
+    let (_, authority) = config
+        .legal_blocks
+        .iter()
+        .find(|(blocked, _)| path_matches_prefix(&path, blocked))?;
+
+    let mut response = error_response(451, config, store);
+    response.add_header("Link", format!("<{}>; rel=\"blocked-by\"", authority));
+    Some(response)
+}
+
+/// The request path as it resolves to a file: decoded, with empty and `.`
+/// segments dropped and `..` applied, so spelling variants of a path compare
+/// equal. A trailing slash is kept.
+fn normalized_path(request: &HttpRequest) -> String {
+    let decoded = percent_decode(&request.path).unwrap_or_else(|| request.path.clone());
+    let mut segments = Vec::new();
+    for segment in decoded.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            other => segments.push(other),
+        }
+    }
+    let mut path = format!("/{}", segments.join("/"));
+    if decoded.ends_with(['/', '\\']) && !segments.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// Whether `path` is `prefix` itself or lies beneath it.
+fn path_matches_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+fn root_status_response(status: u16, config: &ServerConfig) -> HttpResponse {
+    let reason = reason_phrase(status);
+    let body = config.root_body.as_deref().unwrap_or(reason).as_bytes().to_vec();
+    let mut response = HttpResponse::new(status, reason, body);
+    response.add_header("Content-Type", "text/plain");
+    add_retry_after(&mut response, config);
+    response
+}
+
+/// Tells clients of a 503 when to come back. Every 503 goes through here.
+fn add_retry_after(response: &mut HttpResponse, config: &ServerConfig) {
+    if response.status == 503 {
+        response.add_header("Retry-After", config.retry_after.to_string());
+    }
+}
+
+/// Standard reason phrase for a status code.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "NOT FOUND",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// The document named by a directory's `.index` file, if the request is for
+/// a directory that has one.
+fn dot_index_path(request: &HttpRequest, root: &str, path: &Path, store: &dyn FileStore) -> Option<PathBuf> {
+    let dir = requested_dir(request, root, path, store)?;
+    let contents = store.read(&dir.join(".index")).ok()?;
+    let name = String::from_utf8(contents).ok()?;
+    let name = name.trim();
+    // Only a plain file name in the same directory is allowed
+    if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+        return None;
+    }
+    Some(dir.join(name))
+}
+
+/// The directory a request names, given the path it resolved to.
+fn requested_dir(request: &HttpRequest, root: &str, path: &Path, store: &dyn FileStore) -> Option<PathBuf> {
+    if is_index_request(request) {
+        Some(PathBuf::from(root))
+    } else if store.metadata(path).is_ok_and(|meta| meta.is_dir) {
+        Some(path.to_path_buf())
+    } else {
+        None
+    }
+}
+
+fn generate_path(request: &HttpRequest, root: &str, index_files: &[String], store: &dyn FileStore) -> PathBuf {
+    let mut path = PathBuf::from(root);
+    // Malformed escapes were already answered with 400
+    let decoded = percent_decode(&request.path).unwrap_or_else(|| request.path.clone());
+    let relative = match decoded.as_str() {
+        "/" | "/index" => index_file(&path, index_files, store),
+        other => other.trim_start_matches('/'),
+    };
+    path.push(relative);
+    info!("Path = {:?}", path);
+    path
+}
+
+/// The first of `index_files` present in `dir`, else the first name so the
+/// lookup fails as a plain missing file.
+fn index_file<'a>(dir: &Path, index_files: &'a [String], store: &dyn FileStore) -> &'a str {
+    index_files
+        .iter()
+        .find(|name| store.metadata(&dir.join(name)).is_ok_and(|meta| !meta.is_dir))
+        .or(index_files.first())
+        .map_or("index.html", String::as_str)
+}
+
+/// Content types by lowercase file extension.
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "application/javascript"),
+    ("mjs", "application/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+];
+
+pub(crate) fn detect_mime_type(path: &Path) -> &'static str {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| MIME_TYPES.iter().find(|(known, _)| known.eq_ignore_ascii_case(ext)))
+        .map_or("application/octet-stream", |&(_, mime)| mime)
+}
+
+fn handle_404(root: &str, store: &dyn FileStore) -> Vec<u8> {
+    let path = Path::new(root).join("404.html");
+
+    // Read the 404 file and if it's not there, just generate one.
+    match store.read(&path) {
+        Ok(contents) => contents,
+        Err(_) => {
+            b"<h1>404 Not Found</h1>".to_vec()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FileMeta, LocalFs, MemoryStore};
+    use std::fs;
+    use tempfile::tempdir;
+    use std::path::PathBuf;
+
+    /// Helper to run `handle_response` and return the full HTTP response as a String.
+    fn run_handle_response(method: &str, path: &str, static_dir: &std::path::Path) -> String {
+        let mut buffer = Vec::new();
+        let request = HttpRequest {
+            method: Method::from(method),
+            path: path.to_string(),
+            ..Default::default()
+        };
+        handle_response(&mut buffer, &request, &test_config(static_dir), &LocalFs).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    /// Like `run_handle_response`, but with an explicit config.
+    fn run_handle_response_with(method: &str, path: &str, config: &ServerConfig) -> String {
+        let mut buffer = Vec::new();
+        let request = HttpRequest {
+            method: Method::from(method),
+            path: path.to_string(),
+            ..Default::default()
+        };
+        handle_response(&mut buffer, &request, config, &LocalFs).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    /// Helper to build a config serving from `static_dir`.
+    fn test_config(static_dir: &std::path::Path) -> ServerConfig {
+        ServerConfig {
+            root: static_dir.to_str().unwrap().to_string(),
+            ..ServerConfig::default()
+        }
+    }
+
+    /// Helper to build a request for `path` carrying `headers`.
+    fn request(method: Method, path: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method,
+            path: path.to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Helper to create a basic static directory for testing.
+    fn setup_static_dir() -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        let static_dir = dir.path().join("static");
+        fs::create_dir_all(&static_dir).unwrap();
+
+        // Create simple test files
+        fs::write(static_dir.join("index.html"), "<h2>This is the index.html file.</h2>").unwrap();
+        fs::write(static_dir.join("about.html"), "<h2>This is the about.html file.</h2>").unwrap();
+        fs::write(
+            static_dir.join("crow.html"),
+            "<h2>This is the crow.html file.</h2><img src=\"crow.jpeg\">",
+        )
+        .unwrap();
+        fs::write(static_dir.join("crow.jpeg"), b"fakejpegdata").unwrap();
+        fs::write(static_dir.join("404.html"), "This is the 404 file.").unwrap();
+        fs::write(static_dir.join("index.txt"), "plain text file").unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn test_handle_response_root_path() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let response = run_handle_response("GET", "/", &static_dir);
+        assert!(response.contains("200 OK"), "Expected status line");
+        assert!(response.contains("index.html"), "Should serve index page");
+    }
+
+    #[test]
+    fn test_handle_response_index_path() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let response = run_handle_response("GET", "/index.html", &static_dir);
+        assert!(response.contains("200 OK"), "Expected status line");
+        assert!(response.contains("<h2>This is the index.html file.</h2>"));
+    }
+
+    #[test]
+    fn test_handle_response_about_path() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let response = run_handle_response("GET", "/about.html", &static_dir);
+        assert!(response.contains("200 OK"), "Expected status line");
+        assert!(response.contains("<h2>This is the about.html file.</h2>"));
+    }
+
+    #[test]
+    fn test_handle_response_crow_path() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let response = run_handle_response("GET", "/crow.html", &static_dir);
+        assert!(response.contains("200 OK"), "Expected status line");
+        assert!(response.contains("<h2>This is the crow.html file.</h2>"));
+        assert!(response.contains("<img src=\"crow.jpeg\">"));
+    }
+
+    #[test]
+    fn test_handle_response_jpeg() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let mut buffer = Vec::new();
+        let request = HttpRequest {
+            method: Method::Get,
+            path: "/crow.jpeg".to_string(),
+            ..Default::default()
+        };
+        handle_response(&mut buffer, &request, &test_config(&static_dir), &LocalFs).unwrap();
+
+        let response_text = String::from_utf8_lossy(&buffer);
+        assert!(response_text.contains("200 OK"), "Expected HTTP 200");
+        assert!(
+            response_text.contains("Content-Type: image/jpeg"),
+            "Expected JPEG content type"
+        );
+    }
+
+    #[test]
+    fn test_handle_response_not_found() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let response = run_handle_response("GET", "/unknown", &static_dir);
+        assert!(
+            response.contains("404 NOT FOUND"),
+            "Expected HTTP 404 line, got: {}",
+            response
+        );
+        assert!(
+            response.contains("This is the 404 file."),
+            "Expected 404 body, got: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_handle_response_content_length_correct() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let response = run_handle_response("GET", "/index.html", &static_dir);
+        let len_line = response
+            .lines()
+            .find(|l| l.starts_with("Content-Length"))
+            .unwrap();
+        let len_val: usize = len_line.split(':').nth(1).unwrap().trim().parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(
+            body.len(),
+            len_val,
+            "Content-Length header should match actual body size"
+        );
+    }
+
+    #[test]
+    fn test_handle_response_root_status_overrides_root() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let config = ServerConfig {
+            root_status: Some(503),
+            root_body: Some("Parked for now".to_string()),
+            ..test_config(&static_dir)
+        };
+
+        let request = HttpRequest {
+            method: Method::Get,
+            path: "/".to_string(),
+            ..Default::default()
+        };
+        let response = build_response(&request, &config, &LocalFs);
+        assert_eq!(response.status, 503);
+        assert_eq!(response.body, b"Parked for now");
+
+        let request = HttpRequest {
+            method: Method::Get,
+            path: "/about.html".to_string(),
+            ..Default::default()
+        };
+        let response = build_response(&request, &config, &LocalFs);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"<h2>This is the about.html file.</h2>");
+    }
+
+    #[test]
+    fn test_error_response_custom_pages() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::create_dir_all(static_dir.join("errors")).unwrap();
+        fs::write(static_dir.join("errors/403.html"), "<h1>Custom Forbidden</h1>").unwrap();
+        fs::write(static_dir.join("errors/500.html"), "<h1>Custom Oops</h1>").unwrap();
+
+        let mut config = test_config(&static_dir);
+        config.error_pages.insert(403, PathBuf::from("/errors/403.html"));
+        config.error_pages.insert(500, PathBuf::from("errors/500.html"));
+
+        let forbidden = error_response(403, &config, &LocalFs);
+        assert_eq!(forbidden.status, 403);
+        assert_eq!(forbidden.body, b"<h1>Custom Forbidden</h1>");
+
+        let server_error = error_response(500, &config, &LocalFs);
+        assert_eq!(server_error.status, 500);
+        assert_eq!(server_error.body, b"<h1>Custom Oops</h1>");
+    }
+
+    #[test]
+    fn test_error_response_unmapped_uses_builtin() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let mut config = test_config(&static_dir);
+        config.error_pages.insert(500, PathBuf::from("/errors/missing.html"));
+
+        let unavailable = error_response(503, &config, &LocalFs);
+        assert_eq!(unavailable.body, b"<h1>503 Service Unavailable</h1>");
+
+        // A mapped page that can't be read also falls back
+        let server_error = error_response(500, &config, &LocalFs);
+        assert_eq!(server_error.body, b"<h1>500 Internal Server Error</h1>");
+    }
+
+    #[test]
+    fn test_handle_response_from_memory_store() {
+        let mut store = MemoryStore::new();
+        store.insert("/site/hello.html", "<h1>In memory</h1>");
+        let config = ServerConfig {
+            root: "/site".to_string(),
+            ..ServerConfig::default()
+        };
+        let request = HttpRequest {
+            method: Method::Get,
+            path: "/hello.html".to_string(),
+            ..Default::default()
+        };
+
+        let mut buffer = Vec::new();
+        handle_response(&mut buffer, &request, &config, &store).unwrap();
+
+        let response = String::from_utf8(buffer).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.contains("Content-Type: text/html"));
+        assert!(response.ends_with("<h1>In memory</h1>"));
+    }
+
+    #[test]
+    fn test_handle_response_applies_response_delay() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let config = ServerConfig {
+            response_delay: Some(std::time::Duration::from_millis(150)),
+            ..test_config(&static_dir)
+        };
+        let request = HttpRequest {
+            method: Method::Get,
+            path: "/".to_string(),
+            ..Default::default()
+        };
+
+        let start = std::time::Instant::now();
+        let mut buffer = Vec::new();
+        handle_response(&mut buffer, &request, &config, &LocalFs).unwrap();
+
+        assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+        assert!(String::from_utf8(buffer).unwrap().contains("200 OK"));
+    }
+
+    #[test]
+    fn test_canonical_host_redirects_other_hosts() {
+        let config = ServerConfig {
+            canonical_host: Some("example.com".to_string()),
+            ..ServerConfig::default()
+        };
+        let mut request = crate::request::parse_request("GET /docs/page.html?lang=en&q=\"a\" HTTP/1.1\r\n\r\n").unwrap();
+        request.headers.insert("host".to_string(), "www.example.com".to_string());
+
+        let response = build_response(&request, &config, &LocalFs);
+
+        assert_eq!(response.status, 308);
+        assert_eq!(
+            response.header("Location"),
+            Some("http://example.com/docs/page.html?lang=en&q=\"a\"")
+        );
+        let body = String::from_utf8_lossy(&response.body);
+        assert!(body.contains("href=\"http://example.com/docs/page.html?lang=en&amp;q=&quot;a&quot;\""), "got: {}", body);
+
+        // Without a query, nothing is appended
+        request.query = None;
+        let response = build_response(&request, &config, &LocalFs);
+        assert_eq!(response.header("Location"), Some("http://example.com/docs/page.html"));
+    }
+
+    #[test]
+    fn test_canonical_host_passes_through() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let config = ServerConfig {
+            canonical_host: Some("example.com".to_string()),
+            ..test_config(&static_dir)
+        };
+        let mut request = HttpRequest {
+            method: Method::Get,
+            path: "/about.html".to_string(),
+            ..Default::default()
+        };
+        request.headers.insert("host".to_string(), "Example.com".to_string());
+
+        let response = build_response(&request, &config, &LocalFs);
+
+        assert_eq!(response.status, 200);
+    }
+
+    #[test]
+    fn test_welcome_page_served_without_index() {
+        let dir = tempdir().unwrap();
+        let config = ServerConfig {
+            welcome: true,
+            ..test_config(dir.path())
+        };
+
+        let response = run_handle_response_with("GET", "/", &config);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.contains("<h1>Welcome to Rusty Server</h1>"));
+    }
+
+    #[test]
+    fn test_missing_index_is_404_without_welcome() {
+        let dir = tempdir().unwrap();
+
+        let response = run_handle_response_with("GET", "/", &test_config(dir.path()));
+
+        assert!(response.contains("404 NOT FOUND"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_default_robots_allow() {
+        let dir = tempdir().unwrap();
+        let config = ServerConfig {
+            default_robots: Some(RobotsPolicy::Allow),
+            ..test_config(dir.path())
+        };
+
+        let response = run_handle_response_with("GET", "/robots.txt", &config);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.contains("Content-Type: text/plain"));
+        assert!(response.ends_with("User-agent: *\nDisallow:\n"));
+    }
+
+    #[test]
+    fn test_default_robots_disallow() {
+        let dir = tempdir().unwrap();
+        let config = ServerConfig {
+            default_robots: Some(RobotsPolicy::Disallow),
+            ..test_config(dir.path())
+        };
+
+        let response = run_handle_response_with("GET", "/robots.txt", &config);
+
+        assert!(response.ends_with("User-agent: *\nDisallow: /\n"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_real_robots_file_wins() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("robots.txt"), "User-agent: *\nDisallow: /private\n").unwrap();
+        let config = ServerConfig {
+            default_robots: Some(RobotsPolicy::Disallow),
+            ..test_config(dir.path())
+        };
+
+        let response = run_handle_response_with("GET", "/robots.txt", &config);
+
+        assert!(response.ends_with("Disallow: /private\n"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_interim_response_precedes_final() {
+        let mut buffer = Vec::new();
+        write_interim(&mut buffer, 100).unwrap();
+        HttpResponse::new(200, "OK", b"done".to_vec()).write_to(&mut buffer).unwrap();
+
+        assert_eq!(
+            buffer,
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_head_matches_get_headers_without_body() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let get = run_handle_response("GET", "/about.html", &static_dir);
+        let head = run_handle_response("HEAD", "/about.html", &static_dir);
+
+        let (get_headers, get_body) = get.split_once("\r\n\r\n").unwrap();
+        assert_eq!(head, format!("{}\r\n\r\n", get_headers));
+        assert!(head.contains(&format!("Content-Length: {}", get_body.len())));
+    }
+
+    #[test]
+    fn test_head_missing_file_is_404_without_body() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let head = run_handle_response("HEAD", "/missing.html", &static_dir);
+
+        assert!(head.starts_with("HTTP/1.1 404 NOT FOUND"), "got: {}", head);
+        assert!(head.ends_with("\r\n\r\n"), "got: {}", head);
+    }
+
+    #[test]
+    fn test_legal_block_returns_451_with_link() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let config = ServerConfig {
+            legal_blocks: vec![("/crow.html".to_string(), "https://authority.example/notice".to_string())],
+            ..test_config(&static_dir)
+        };
+
+        let blocked = run_handle_response_with("GET", "/crow.html", &config);
+        assert!(blocked.starts_with("HTTP/1.1 451 Unavailable For Legal Reasons"), "got: {}", blocked);
+        assert!(blocked.contains("Link: <https://authority.example/notice>; rel=\"blocked-by\"\r\n"));
+
+        let allowed = run_handle_response_with("GET", "/about.html", &config);
+        assert!(allowed.starts_with("HTTP/1.1 200 OK"), "got: {}", allowed);
+        assert!(!allowed.contains("Link:"));
+    }
+
+    #[test]
+    fn test_legal_block_matches_encoded_and_unnormalized_paths() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::create_dir(static_dir.join("docs")).unwrap();
+        let config = ServerConfig {
+            legal_blocks: vec![("/crow.html".to_string(), "https://authority.example/notice".to_string())],
+            ..test_config(&static_dir)
+        };
+
+        for path in ["//crow.html", "/./crow.html", "/%63row.html", "/docs/../crow.html", "/crow%2ehtml"] {
+            let response = run_handle_response_with("GET", path, &config);
+            assert!(
+                response.starts_with("HTTP/1.1 451 Unavailable For Legal Reasons"),
+                "{}: {}",
+                path,
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalized_path() {
+        let normalize = |path: &str| {
+            normalized_path(&HttpRequest {
+                path: path.to_string(),
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(normalize("/"), "/");
+        assert_eq!(normalize("//a///b.html"), "/a/b.html");
+        assert_eq!(normalize("/./a/%62.html"), "/a/b.html");
+        assert_eq!(normalize("/a/../b/"), "/b/");
+        assert_eq!(normalize("/../.."), "/");
+    }
+
+    #[test]
+    fn test_path_matches_prefix() {
+        assert!(path_matches_prefix("/docs", "/docs"));
+        assert!(path_matches_prefix("/docs/a.html", "/docs"));
+        assert!(path_matches_prefix("/docs/a.html", "/docs/"));
+        assert!(!path_matches_prefix("/docsearch", "/docs"));
+        assert!(!path_matches_prefix("/", "/docs"));
+    }
+
+    #[test]
+    fn test_unsupported_method_is_405_with_allow() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let response = run_handle_response("DELETE", "/index.html", &static_dir);
+
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "got: {}", response);
+        assert!(response.contains("Allow: GET, HEAD\r\n"), "got: {}", response);
+        assert!(!response.contains("This is the index.html file."));
+    }
+
+    #[test]
+    fn test_dot_index_selects_directory_document() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::create_dir_all(static_dir.join("docs")).unwrap();
+        fs::write(static_dir.join("docs/.index"), "home.html\n").unwrap();
+        fs::write(static_dir.join("docs/home.html"), "<h1>Docs home</h1>").unwrap();
+        let config = ServerConfig {
+            dot_index: true,
+            ..test_config(&static_dir)
+        };
+
+        let response = run_handle_response_with("GET", "/docs", &config);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.ends_with("<h1>Docs home</h1>"));
+
+        // Without the flag the directory isn't served
+        let response = run_handle_response_with("GET", "/docs", &test_config(&static_dir));
+        assert!(response.contains("404 NOT FOUND"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_dot_index_at_root_and_rejects_escapes() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::write(static_dir.join(".index"), "about.html").unwrap();
+        let config = ServerConfig {
+            dot_index: true,
+            ..test_config(&static_dir)
+        };
+
+        let response = run_handle_response_with("GET", "/", &config);
+        assert!(response.ends_with("<h2>This is the about.html file.</h2>"), "got: {}", response);
+
+        fs::write(static_dir.join(".index"), "../secret.html").unwrap();
+        let response = run_handle_response_with("GET", "/", &config);
+        assert!(response.ends_with("<h2>This is the index.html file.</h2>"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_warm_up_reads_index_and_tolerates_missing_one() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        assert!(warm_up(&test_config(&static_dir), &LocalFs));
+
+        fs::remove_file(static_dir.join("index.html")).unwrap();
+        assert!(!warm_up(&test_config(&static_dir), &LocalFs));
+    }
+
+    #[test]
+    fn test_byte_range_forms() {
+        assert_eq!(byte_range(None, 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=2-4"), 10), ByteRange::Partial(2, 4));
+        assert_eq!(byte_range(Some("bytes=2-99"), 10), ByteRange::Partial(2, 9));
+        assert_eq!(byte_range(Some("bytes=5-"), 10), ByteRange::Partial(5, 9));
+        assert_eq!(byte_range(Some("bytes=-3"), 10), ByteRange::Partial(7, 9));
+        assert_eq!(byte_range(Some("bytes=-30"), 10), ByteRange::Partial(0, 9));
+        assert_eq!(byte_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=-0"), 10), ByteRange::Unsatisfiable);
+        // Unparseable, multi-range and other units fall back to the full body
+        assert_eq!(byte_range(Some("bytes=4-2"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=0-1,4-5"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("items=0-1"), 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_range_request_returns_partial_content() {
+        let dir = setup_static_dir();
+        let config = test_config(&dir.path().join("static"));
+
+        // index.txt holds "plain text file"
+        let response = build_response(
+            &request(Method::Get, "/index.txt", &[("Range", "bytes=0-4")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, b"plain");
+        assert_eq!(response.header("Content-Range"), Some("bytes 0-4/15"));
+        assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+
+        let response = build_response(
+            &request(Method::Get, "/index.txt", &[("Range", "bytes=11-")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.body, b"file");
+        assert_eq!(response.header("Content-Range"), Some("bytes 11-14/15"));
+
+        let response = build_response(
+            &request(Method::Get, "/index.txt", &[("Range", "bytes=-4")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.body, b"file");
+        assert_eq!(response.header("Content-Range"), Some("bytes 11-14/15"));
+    }
+
+    #[test]
+    fn test_head_ignores_range() {
+        let dir = setup_static_dir();
+        let config = test_config(&dir.path().join("static"));
+        let request = HttpRequest {
+            method: Method::Head,
+            ..request(Method::Get, "/index.txt", &[("Range", "bytes=0-4")])
+        };
+
+        let response = build_response(&request, &config, &LocalFs);
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Range"), None);
+        assert_eq!(response.body_len(), 15);
+        let mut buffer = Vec::new();
+        handle_response(&mut buffer, &request, &config, &LocalFs).unwrap();
+        let head = String::from_utf8(buffer).unwrap();
+        assert!(head.contains("Content-Length: 15\r\n"), "got: {}", head);
+        assert!(head.ends_with("\r\n\r\n"), "got: {}", head);
+    }
+
+    #[test]
+    fn test_range_request_out_of_bounds_is_416() {
+        let dir = setup_static_dir();
+        let config = test_config(&dir.path().join("static"));
+
+        let response = build_response(
+            &request(Method::Get, "/index.txt", &[("Range", "bytes=15-20")]),
+            &config,
+            &LocalFs,
+        );
+
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */15"));
+    }
+
+    #[test]
+    fn test_full_response_advertises_ranges() {
+        let dir = setup_static_dir();
+        let response = run_handle_response("GET", "/index.txt", &dir.path().join("static"));
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.contains("Accept-Ranges: bytes\r\n"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_responses_carry_a_current_date() {
+        let dir = setup_static_dir();
+        let config = test_config(&dir.path().join("static"));
+        let before = SystemTime::now() - Duration::from_secs(1);
+
+        for path in ["/index.html", "/missing.html"] {
+            let request = HttpRequest {
+                path: path.to_string(),
+                ..Default::default()
+            };
+            let response = build_response(&request, &config, &LocalFs);
+
+            let date = response.header("Date").expect("Date header");
+            let parsed = httpdate::parse_http_date(date).unwrap();
+            assert!(date.ends_with(" GMT"), "got: {}", date);
+            assert!(parsed >= before && parsed <= SystemTime::now(), "got: {}", date);
+        }
+    }
+
+    #[test]
+    fn test_server_header_is_sent_unless_disabled() {
+        let dir = setup_static_dir();
+        let mut config = test_config(&dir.path().join("static"));
+        let request = HttpRequest {
+            path: "/".to_string(),
+            ..Default::default()
+        };
+
+        let response = build_response(&request, &config, &LocalFs);
+        assert_eq!(
+            response.header("Server"),
+            Some(concat!("rusty_server/", env!("CARGO_PKG_VERSION")))
+        );
+
+        config.server_header = None;
+        let response = build_response(&request, &config, &LocalFs);
+        assert_eq!(response.header("Server"), None);
+    }
+
+    /// Writes a `len`-byte file of non-repeating-ish bytes to `path`.
+    fn write_large_file(path: &Path, len: usize) -> Vec<u8> {
+        let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        fs::write(path, &contents).unwrap();
+        contents
+    }
+
+    /// Splits a written response at the blank line ending the headers.
+    fn split_head(written: &[u8]) -> (String, &[u8]) {
+        let end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        (String::from_utf8_lossy(&written[..end]).into_owned(), &written[end..])
+    }
+
+    #[test]
+    fn test_large_file_is_streamed_intact() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let contents = write_large_file(&static_dir.join("video.mp4"), 3 * 1024 * 1024 + 17);
+        let request = HttpRequest {
+            path: "/video.mp4".to_string(),
+            ..Default::default()
+        };
+
+        let mut response = build_response(&request, &test_config(&static_dir), &LocalFs);
+        assert!(response.stream.is_some() && response.body.is_empty());
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+
+        let (head, body) = split_head(&written);
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "got: {}", head);
+        assert!(head.contains(&format!("Content-Length: {}\r\n", contents.len())), "got: {}", head);
+        assert!(body == contents.as_slice(), "streamed body differs from the file");
+    }
+
+    #[test]
+    fn test_range_of_streamed_file() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let contents = write_large_file(&static_dir.join("video.mp4"), 2 * 1024 * 1024);
+
+        let request = request(Method::Get, "/video.mp4", &[("Range", "bytes=1048570-1048585")]);
+
+        let mut response = build_response(&request, &test_config(&static_dir), &LocalFs);
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+
+        let (head, body) = split_head(&written);
+        assert!(head.starts_with("HTTP/1.1 206 Partial Content"), "got: {}", head);
+        assert!(head.contains("Content-Range: bytes 1048570-1048585/2097152\r\n"), "got: {}", head);
+        assert_eq!(body, &contents[1048570..=1048585]);
+    }
+
+    #[test]
+    fn test_unsatisfiable_range_of_streamed_file_with_one_open_file() {
+        use crate::storage::LimitedStore;
+        use std::sync::mpsc;
+
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        write_large_file(&static_dir.join("video.mp4"), 2 * 1024 * 1024);
+        fs::write(static_dir.join("416.html"), "custom range page").unwrap();
+        let mut config = test_config(&static_dir);
+        config.error_pages.insert(416, PathBuf::from("/416.html"));
+
+        // A deadlock would hang forever, so wait on another thread
+        let (done, result) = mpsc::channel();
+        thread::spawn(move || {
+            let store = LimitedStore::new(LocalFs, 1);
+            let response = build_response(
+                &request(Method::Get, "/video.mp4", &[("Range", "bytes=3000000-")]),
+                &config,
+                &store,
+            );
+            done.send(response).unwrap();
+        });
+        let response = result.recv_timeout(Duration::from_secs(5)).expect("416 with a custom page deadlocked");
+
+        assert_eq!(response.status, 416);
+        assert_eq!(response.body, b"custom range page");
+    }
+
+    #[test]
+    fn test_if_modified_since_yields_304() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let config = test_config(&static_dir);
+        let modified = fs::metadata(static_dir.join("about.html")).unwrap().modified().unwrap();
+        let request = |since: SystemTime| HttpRequest {
+            path: "/about.html".to_string(),
+            headers: [("if-modified-since".to_string(), httpdate::fmt_http_date(since))]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+
+        let response = build_response(&request(modified), &config, &LocalFs);
+        assert_eq!(response.status, 304);
+        assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.header("Last-Modified"), Some(httpdate::fmt_http_date(modified).as_str()));
+        let bytes = String::from_utf8(response.to_bytes()).unwrap();
+        assert!(bytes.starts_with("HTTP/1.1 304 Not Modified\r\n"), "got: {}", bytes);
+        assert!(!bytes.contains("Content-Length"), "got: {}", bytes);
+        assert!(bytes.ends_with("\r\n\r\n"));
+
+        // An older date means the client's copy is stale
+        let response = build_response(&request(modified - Duration::from_secs(60)), &config, &LocalFs);
+        assert_eq!(response.status, 200);
+        assert!(response.header("Last-Modified").is_some());
+    }
+
+    #[test]
+    fn test_etag_is_sent_and_if_none_match_yields_304() {
+        let dir = setup_static_dir();
+        let config = test_config(&dir.path().join("static"));
+        let request = |headers: &[(&str, &str)]| HttpRequest {
+            path: "/about.html".to_string(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Default::default()
+        };
+
+        let response = build_response(&request(&[]), &config, &LocalFs);
+        assert_eq!(response.status, 200);
+        let etag = response.header("ETag").unwrap().to_string();
+        assert!(etag.starts_with("W/\"") && etag.ends_with('"'), "etag: {}", etag);
+
+        // Recomputed from metadata, so the same on the next request
+        let response = build_response(&request(&[("if-none-match", &etag)]), &config, &LocalFs);
+        assert_eq!(response.status, 304);
+        assert_eq!(response.header("ETag"), Some(etag.as_str()));
+        assert!(response.body.is_empty());
+
+        let response = build_response(&request(&[("if-none-match", "\"other\", W/\"x\"")]), &config, &LocalFs);
+        assert_eq!(response.status, 200);
+
+        // A mismatched ETag wins over a matching If-Modified-Since
+        let response = build_response(
+            &request(&[("if-none-match", "\"other\""), ("if-modified-since", "Fri, 01 Jan 2100 00:00:00 GMT")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.status, 200);
+    }
+
+    #[test]
+    fn test_etag_matches_lists_wildcards_and_weak_tags() {
+        assert!(etag_matches("\"a\"", "\"a\""));
+        assert!(etag_matches("\"b\", W/\"a\"", "\"a\""));
+        assert!(etag_matches("*", "\"a\""));
+        assert!(!etag_matches("\"b\"", "\"a\""));
+        assert!(etag_matches("\"a\"", "W/\"a\""));
+    }
+
+    #[test]
+    fn test_mtime_etags_are_weak_and_content_etags_strong() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let about = request(Method::Get, "/about.html", &[]);
+
+        let mtime = build_response(&about, &test_config(&static_dir), &LocalFs);
+        let etag = mtime.header("ETag").unwrap();
+        assert!(etag.starts_with("W/\""), "etag: {}", etag);
+
+        let config = ServerConfig {
+            etag_mode: EtagMode::Content,
+            ..test_config(&static_dir)
+        };
+        let response = build_response(&about, &config, &LocalFs);
+        let etag = response.header("ETag").unwrap().to_string();
+        assert!(etag.starts_with('"'), "etag: {}", etag);
+        let revalidate = request(Method::Get, "/about.html", &[("If-None-Match", &etag)]);
+        assert_eq!(build_response(&revalidate, &config, &LocalFs).status, 304);
+
+        // Same size, new bytes: a content ETag notices
+        fs::write(static_dir.join("about.html"), "<h2>This is the ABOUT.html file.</h2>").unwrap();
+        assert_eq!(build_response(&revalidate, &config, &LocalFs).status, 200);
+
+        let config = ServerConfig {
+            etag_weak: Some(true),
+            ..config
+        };
+        let etag = build_response(&about, &config, &LocalFs).header("ETag").unwrap().to_string();
+        assert!(etag.starts_with("W/\""), "etag: {}", etag);
+    }
+
+    #[test]
+    fn test_gzip_for_capable_clients_only() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let page = "<p>hello</p>".repeat(200);
+        fs::write(static_dir.join("big.html"), &page).unwrap();
+        let config = test_config(&static_dir);
+
+        let response = build_response(
+            &request(Method::Get, "/big.html", &[("Accept-Encoding", "gzip, deflate")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert!(response.header("ETag").unwrap().starts_with("W/"));
+        let mut decoded = String::new();
+        GzDecoder::new(&response.body[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, page);
+        let bytes = String::from_utf8_lossy(&response.to_bytes()).into_owned();
+        assert!(bytes.contains(&format!("Content-Length: {}\r\n", response.body.len())));
+
+        for accept_encoding in [None, Some("identity"), Some("gzip;q=0")] {
+            let headers = Vec::from_iter(accept_encoding.map(|value| ("Accept-Encoding", value)));
+            let response = build_response(&request(Method::Get, "/big.html", &headers), &config, &LocalFs);
+            assert_eq!(response.header("Content-Encoding"), None);
+            assert_eq!(response.body, page.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_serves_precompressed_sibling_to_gzip_clients() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::write(static_dir.join("app.js"), "console.log(1);").unwrap();
+        fs::write(static_dir.join("app.js.gz"), b"\x1f\x8bprecompressed").unwrap();
+        let config = test_config(&static_dir);
+
+        let response = build_response(
+            &request(Method::Get, "/app.js", &[("Accept-Encoding", "gzip")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"\x1f\x8bprecompressed");
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Content-Type"), Some("application/javascript; charset=utf-8"));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+
+        let response = build_response(&request(Method::Get, "/app.js", &[]), &config, &LocalFs);
+        assert_eq!(response.body, b"console.log(1);");
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn test_skips_sibling_older_than_the_file() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::write(static_dir.join("app.js.gz"), b"\x1f\x8bstale").unwrap();
+        fs::write(static_dir.join("app.js"), "console.log(2);").unwrap();
+        let edited = SystemTime::now();
+        fs::File::options()
+            .write(true)
+            .open(static_dir.join("app.js.gz"))
+            .unwrap()
+            .set_modified(edited - Duration::from_secs(60))
+            .unwrap();
+        fs::File::options()
+            .write(true)
+            .open(static_dir.join("app.js"))
+            .unwrap()
+            .set_modified(edited)
+            .unwrap();
+        let config = test_config(&static_dir);
+
+        let response = build_response(
+            &request(Method::Get, "/app.js", &[("Accept-Encoding", "gzip")]),
+            &config,
+            &LocalFs,
+        );
+
+        assert_eq!(response.body, b"console.log(2);");
+        assert_eq!(response.header("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn test_without_sibling_serves_plain_file() {
+        let dir = setup_static_dir();
+        let config = test_config(&dir.path().join("static"));
+
+        let response = build_response(
+            &request(Method::Get, "/about.html", &[("Accept-Encoding", "gzip")]),
+            &config,
+            &LocalFs,
+        );
+
+        assert_eq!(response.body, b"<h2>This is the about.html file.</h2>");
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    #[test]
+    fn test_gzip_skips_small_and_binary_bodies() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::write(static_dir.join("big.jpeg"), vec![7u8; 4096]).unwrap();
+        let config = test_config(&static_dir);
+
+        let response = build_response(
+            &request(Method::Get, "/about.html", &[("Accept-Encoding", "gzip")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.header("Content-Encoding"), None);
+
+        let response = build_response(
+            &request(Method::Get, "/big.jpeg", &[("Accept-Encoding", "gzip")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.body.len(), 4096);
+    }
+
+    #[test]
+    fn test_save_data_compresses_bodies_below_the_threshold() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        let page = "<p>saving</p>".repeat(60);
+        assert!(page.len() < MIN_COMPRESS_SIZE);
+        fs::write(static_dir.join("small.html"), &page).unwrap();
+        let config = test_config(&static_dir);
+
+        let baseline = build_response(
+            &request(Method::Get, "/small.html", &[("Accept-Encoding", "gzip")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(baseline.header("Content-Encoding"), None);
+
+        let response = build_response(
+            &request(Method::Get, "/small.html", &[("Accept-Encoding", "gzip"), ("Save-Data", "on")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding, Save-Data"));
+        let mut decoded = String::new();
+        GzDecoder::new(&response.body[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, page);
+
+        // Too small to shrink, so it goes out as is
+        let response = build_response(
+            &request(Method::Get, "/about.html", &[("Accept-Encoding", "gzip"), ("Save-Data", "on")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.body, b"<h2>This is the about.html file.</h2>");
+    }
+
+    #[test]
+    fn test_spa_serves_index_for_client_routes() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            spa: true,
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = run_handle_response_with("GET", "/dashboard", &config);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.contains("This is the index.html file."), "got: {}", response);
+
+        let response = run_handle_response_with("GET", "/missing.png", &config);
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"), "got: {}", response);
+
+        // Existing files are still served as themselves
+        let response = run_handle_response_with("GET", "/about.html", &config);
+        assert!(response.contains("This is the about.html file."), "got: {}", response);
+    }
+
+    #[test]
+    fn test_spa_off_by_default_and_without_index() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+
+        let response = run_handle_response("GET", "/dashboard", &static_dir);
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"), "got: {}", response);
+
+        // With no index to fall back to, it's a plain 404 rather than a loop
+        fs::remove_file(static_dir.join("index.html")).unwrap();
+        let config = ServerConfig {
+            spa: true,
+            ..test_config(&static_dir)
+        };
+        let response = run_handle_response_with("GET", "/dashboard", &config);
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_cors_echoes_allowed_origin() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            cors_origins: vec!["https://app.example".to_string(), "https://admin.example".to_string()],
+            ..test_config(&dir.path().join("static"))
+        };
+
+        fs::write(dir.path().join("static/big.html"), "<p>listing</p>".repeat(200)).unwrap();
+        let request = request(Method::Get, "/big.html", &[("Origin", "https://admin.example")]);
+
+        let response = build_response(&request, &config, &LocalFs);
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("https://admin.example"));
+        // Compression and CORS both vary the response, under one header
+        let vary: Vec<&str> = response
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(vary, ["Accept-Encoding, Origin"]);
+    }
+
+    #[test]
+    fn test_add_vary_merges_and_dedupes() {
+        let mut response = HttpResponse::new(200, reason_phrase(200), Vec::new());
+
+        add_vary(&mut response, "Accept-Encoding");
+        add_vary(&mut response, "Origin");
+        add_vary(&mut response, "accept-encoding");
+
+        assert_eq!(response.headers, [("Vary".to_string(), "Accept-Encoding, Origin".to_string())]);
+    }
+
+    #[test]
+    fn test_cors_ignores_other_origins() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            cors_origins: vec!["https://app.example".to_string()],
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = build_response(
+            &request(Method::Get, "/about.html", &[("Origin", "https://evil.example")]),
+            &config,
+            &LocalFs,
+        );
+
+        // Still served; the browser is what withholds it from the page
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+
+        // Nothing is added unless CORS is configured
+        let config = test_config(&dir.path().join("static"));
+        let response = build_response(
+            &request(Method::Get, "/about.html", &[("Origin", "https://app.example")]),
+            &config,
+            &LocalFs,
+        );
+        assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    #[test]
+    fn test_cors_wildcard_allows_any_origin() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            cors_origins: vec!["*".to_string()],
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = build_response(
+            &request(Method::Get, "/about.html", &[("Origin", "https://anyone.example")]),
+            &config,
+            &LocalFs,
+        );
+
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    #[test]
+    fn test_cors_preflight_is_204_with_allowed_methods() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            cors_origins: vec!["https://app.example".to_string()],
+            ..test_config(&dir.path().join("static"))
+        };
+        let preflight = request(
+            Method::Options,
+            "/about.html",
+            &[
+                ("Origin", "https://app.example"),
+                ("Access-Control-Request-Method", "GET"),
+                ("Access-Control-Request-Headers", "x-token"),
+            ],
+        );
+
+        let response = build_response(&preflight, &config, &LocalFs);
+
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("https://app.example"));
+        assert_eq!(response.header("Access-Control-Allow-Methods"), Some("GET, HEAD"));
+        assert_eq!(response.header("Access-Control-Allow-Headers"), Some("x-token"));
+
+        let preflight = request(
+            Method::Options,
+            "/about.html",
+            &[
+                ("Origin", "https://evil.example"),
+                ("Access-Control-Request-Method", "GET"),
+            ],
+        );
+        let response = build_response(&preflight, &config, &LocalFs);
+        assert_eq!(response.status, 403);
+        assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn test_auth_missing_credentials_is_401_with_challenge() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            auth: Some("admin:s3cret".to_string()),
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = build_response(&request(Method::Get, "/about.html", &[]), &config, &LocalFs);
+
+        assert_eq!(response.status, 401);
+        assert_eq!(
+            response.header("WWW-Authenticate"),
+            Some("Basic realm=\"rusty_server\", charset=\"UTF-8\"")
+        );
+        assert!(!String::from_utf8_lossy(&response.body).contains("about.html file"));
+    }
+
+    #[test]
+    fn test_auth_wrong_credentials_is_401() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            auth: Some("admin:s3cret".to_string()),
+            ..test_config(&dir.path().join("static"))
+        };
+
+        for header in [
+            format!("Basic {}", BASE64_STANDARD.encode("admin:guess")),
+            format!("Basic {}", BASE64_STANDARD.encode("admin:s3cre")),
+            format!("Bearer {}", BASE64_STANDARD.encode("admin:s3cret")),
+            "Basic not-base64!".to_string(),
+        ] {
+            let response = build_response(
+                &request(Method::Get, "/about.html", &[("Authorization", &header)]),
+                &config,
+                &LocalFs,
+            );
+            assert_eq!(response.status, 401, "{}", header);
+        }
+    }
+
+    #[test]
+    fn test_auth_correct_credentials_are_served() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            auth: Some("admin:s3cret".to_string()),
+            ..test_config(&dir.path().join("static"))
+        };
+        let header = format!("basic {}", BASE64_STANDARD.encode("admin:s3cret"));
+
+        let response = build_response(
+            &request(Method::Get, "/about.html", &[("Authorization", &header)]),
+            &config,
+            &LocalFs,
+        );
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("WWW-Authenticate"), None);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"admin:s3cret", b"admin:s3cret"));
+        assert!(!constant_time_eq(b"admin:s3cre", b"admin:s3cret"));
+        assert!(!constant_time_eq(b"admin:s3cretx", b"admin:s3cret"));
+        assert!(!constant_time_eq(b"", b"admin:s3cret"));
+    }
+
+    #[test]
+    fn test_denied_method_gets_405_with_remaining_allow() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            denied_methods: vec![Method::Head],
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = run_handle_response_with("HEAD", "/", &config);
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed"), "got: {}", response);
+        assert!(response.contains("Allow: GET\r\n"), "got: {}", response);
+
+        let response = run_handle_response_with("GET", "/", &config);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_put_round_trips_through_get() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            enable_upload: true,
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = build_response(&HttpRequest {
+            body: b"first draft".to_vec(),
+            ..request(Method::Put, "/notes/today.txt", &[])
+        }, &config, &LocalFs);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Location"), Some("/notes/today.txt"));
+
+        let response = run_handle_response_with("GET", "/notes/today.txt", &config);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.ends_with("\r\n\r\nfirst draft"), "got: {}", response);
+
+        // Replacing it is a 204
+        let response = build_response(&HttpRequest {
+            body: b"final".to_vec(),
+            ..request(Method::Put, "/notes/today.txt", &[])
+        }, &config, &LocalFs);
+        assert_eq!(response.status, 204);
+        assert!(run_handle_response_with("GET", "/notes/today.txt", &config).ends_with("\r\n\r\nfinal"));
+    }
+
+    #[test]
+    fn test_put_with_transfer_encoding_is_411_and_leaves_file_alone() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            enable_upload: true,
+            ..test_config(&dir.path().join("static"))
+        };
+        let chunked = [("Transfer-Encoding", "chunked")];
+
+        let response = build_response(&request(Method::Put, "/about.html", &chunked), &config, &LocalFs);
+
+        assert_eq!(response.status, 411);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("static/about.html")).unwrap(),
+            "<h2>This is the about.html file.</h2>"
+        );
+        let response = build_response(&request(Method::Put, "/new.txt", &chunked), &config, &LocalFs);
+        assert_eq!(response.status, 411);
+        assert!(!dir.path().join("static/new.txt").exists());
+    }
+
+    #[test]
+    fn test_put_is_405_unless_uploads_enabled() {
+        let dir = setup_static_dir();
+        let config = test_config(&dir.path().join("static"));
+
+        let response = build_response(&HttpRequest {
+            body: b"data".to_vec(),
+            ..request(Method::Put, "/new.txt", &[])
+        }, &config, &LocalFs);
+
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD"));
+        assert!(!dir.path().join("static/new.txt").exists());
+    }
+
+    #[test]
+    fn test_put_cannot_escape_root() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            enable_upload: true,
+            ..test_config(&dir.path().join("static"))
+        };
+
+        for path in ["/../escaped.txt", "/%2e%2e/escaped.txt", "/sub/../../escaped.txt"] {
+            let response = build_response(&HttpRequest {
+            body: b"data".to_vec(),
+            ..request(Method::Put, path, &[])
+        }, &config, &LocalFs);
+            assert_eq!(response.status, 403, "{}", path);
+        }
+        assert!(!dir.path().join("escaped.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_put_cannot_write_through_link_out_of_root() {
+        let dir = setup_static_dir();
+        let outside = tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("static/out")).unwrap();
+        let config = ServerConfig {
+            enable_upload: true,
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = build_response(&HttpRequest {
+            body: b"data".to_vec(),
+            ..request(Method::Put, "/out/new/escaped.txt", &[])
+        }, &config, &LocalFs);
+
+        assert_eq!(response.status, 403);
+        assert!(!outside.path().join("new").exists());
+    }
+
+    #[test]
+    fn test_delete_removes_existing_file() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            enable_upload: true,
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = build_response(&request(Method::Delete, "/about.html", &[]), &config, &LocalFs);
+
+        assert_eq!(response.status, 204);
+        assert!(!dir.path().join("static/about.html").exists());
+        let response = run_handle_response_with("GET", "/about.html", &config);
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_delete_missing_file_is_404() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            enable_upload: true,
+            ..test_config(&dir.path().join("static"))
+        };
+
+        let response = build_response(&request(Method::Delete, "/nothing-here.html", &[]), &config, &LocalFs);
+
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn test_delete_is_405_unless_uploads_enabled() {
+        let dir = setup_static_dir();
+        let config = test_config(&dir.path().join("static"));
+
+        let response = build_response(&request(Method::Delete, "/about.html", &[]), &config, &LocalFs);
+
+        assert_eq!(response.status, 405);
+        assert!(dir.path().join("static/about.html").exists());
+    }
+
+    #[test]
+    fn test_delete_directory_needs_recursive_delete() {
+        let dir = setup_static_dir();
+        fs::create_dir_all(dir.path().join("static/docs/old")).unwrap();
+        fs::write(dir.path().join("static/docs/old/a.txt"), "a").unwrap();
+        let config = ServerConfig {
+            enable_upload: true,
+            ..test_config(&dir.path().join("static"))
+        };
 
-use crate::request::HttpRequest;
+        let response = build_response(&request(Method::Delete, "/docs", &[]), &config, &LocalFs);
+        assert_eq!(response.status, 409);
+        assert!(dir.path().join("static/docs/old/a.txt").exists());
 
-//pub fn handle_response(stream: &mut TcpStream, request: &HttpRequest, root: &str) -> std::io::Result<()> {
-pub fn handle_response<T: Write>(mut stream: T, request: &HttpRequest, root: &str) -> std::io::Result<()> {
+        let config = ServerConfig {
+            recursive_delete: true,
+            ..config
+        };
+        let response = build_response(&request(Method::Delete, "/docs/", &[]), &config, &LocalFs);
+        assert_eq!(response.status, 204);
+        assert!(!dir.path().join("static/docs").exists());
+    }
 
-    info!("root = {}", root);
-    let path = generate_path(request, root);
-    info!("path = {}", path.display());
+    #[test]
+    fn test_delete_never_removes_root_or_outside_it() {
+        let dir = setup_static_dir();
+        fs::write(dir.path().join("outside.txt"), "keep").unwrap();
+        let config = ServerConfig {
+            enable_upload: true,
+            recursive_delete: true,
+            ..test_config(&dir.path().join("static"))
+        };
 
-    let content_type = detect_mime_type(&path);
+        for path in ["/", "/.", "/../outside.txt", "/%2e%2e/outside.txt"] {
+            let response = build_response(&request(Method::Delete, path, &[]), &config, &LocalFs);
+            assert_eq!(response.status, 403, "{}", path);
+        }
+        assert!(dir.path().join("static/index.html").exists());
+        assert!(dir.path().join("outside.txt").exists());
+    }
 
-    // Read the file contents as bytes
-    let (status_line, body) = match fs::read(&path) {
-        Ok(contents) => ("HTTP/1.1 200 OK", contents),
-        Err(_) => ("HTTP/1.1 404 NOT FOUND", handle_404())
-    };
+    #[test]
+    fn test_put_to_directory_is_409() {
+        let dir = setup_static_dir();
+        fs::create_dir(dir.path().join("static/docs")).unwrap();
+        let config = ServerConfig {
+            enable_upload: true,
+            ..test_config(&dir.path().join("static"))
+        };
 
-    // Build and send the response
-    let header = format!(
-        "{status_line}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
-        body.len()
-    );
+        assert_eq!(build_response(&HttpRequest {
+            body: b"data".to_vec(),
+            ..request(Method::Put, "/docs", &[])
+        }, &config, &LocalFs).status, 409);
+        assert_eq!(build_response(&HttpRequest {
+            body: b"data".to_vec(),
+            ..request(Method::Put, "/", &[])
+        }, &config, &LocalFs).status, 409);
+    }
 
-    stream.write_all(header.as_bytes())?;
-    stream.write_all(&body)?;
-    stream.flush()?;
+    #[test]
+    fn test_autoindex_lists_directory_without_index() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("site");
+        fs::create_dir_all(root.join("photos")).unwrap();
+        fs::write(root.join("notes.txt"), "notes").unwrap();
+        fs::write(root.join("a&b.html"), "amp").unwrap();
+        let config = ServerConfig {
+            autoindex: true,
+            ..test_config(&root)
+        };
 
-    Ok(())
-}
+        let response = run_handle_response_with("GET", "/", &config);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.contains("<a href=\"/notes.txt\">notes.txt</a>"), "got: {}", response);
+        assert!(response.contains("<a href=\"/photos/\">photos/</a>"), "got: {}", response);
+        assert!(response.contains("a&amp;b.html"), "got: {}", response);
 
+        fs::write(root.join("photos/cat.jpeg"), "meow").unwrap();
+        let response = run_handle_response_with("GET", "/photos", &config);
+        assert!(response.contains("<a href=\"/photos/cat.jpeg\">cat.jpeg</a>"), "got: {}", response);
+    }
 
+    #[test]
+    fn test_autoindex_links_encode_special_names() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("100% done #1?.txt"), "done").unwrap();
+        fs::write(dir.path().join("café.txt"), "coffee").unwrap();
+        let config = ServerConfig {
+            autoindex: true,
+            ..test_config(dir.path())
+        };
 
-fn generate_path(request: &HttpRequest, root: &str) -> PathBuf {
-    let mut path = PathBuf::from(root);
-    let relative = match request.path.as_str() {
-        "/" | "/index" => "index.html",
-        other => other.trim_start_matches('/'),
-    };
-    path.push(relative);
-    info!("Path = {:?}", path);
-    path
-}
+        let response = run_handle_response_with("GET", "/", &config);
+        assert!(
+            response.contains("<a href=\"/100%25%20done%20%231%3F.txt\">100% done #1?.txt</a>"),
+            "got: {}",
+            response
+        );
+        assert!(response.contains("<a href=\"/caf%C3%A9.txt\">café.txt</a>"), "got: {}", response);
 
-fn detect_mime_type(path: &Path) -> &'static str {
-    match path.extension().and_then(|ext| ext.to_str()) {
-        Some("html") => "text/html",
-        Some("css")  => "text/css",
-        Some("js")   => "application/javascript",
-        Some("png")  => "image/png",
-        Some("jpg") | Some("jpeg") => "image/jpeg",
-        Some("gif")  => "image/gif",
-        _ => "application/octet-stream",
+        // The links lead back to the files
+        let response = run_handle_response_with("GET", "/100%25%20done%20%231%3F.txt", &config);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.ends_with("done"), "got: {}", response);
     }
-}
 
-fn handle_404() -> Vec<u8> {
-    let path_str = "static/404.html";
-    let path = Path::new(path_str);
+    #[test]
+    fn test_autoindex_etag_revalidates_until_the_directory_changes() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "notes").unwrap();
+        let config = ServerConfig {
+            autoindex: true,
+            ..test_config(dir.path())
+        };
 
-    // Read the 404 file and if it's not there, just generate one.
-    match fs::read(&path) {
-        Ok(contents) => contents,
-        Err(_) => {
-            b"<h1>404 Not Found</h1>".to_vec()
-        }
+        let response = build_response(&request(Method::Get, "/", &[]), &config, &LocalFs);
+        assert_eq!(response.status, 200);
+        let etag = response.header("ETag").unwrap().to_string();
+
+        let revalidate = request(Method::Get, "/", &[("If-None-Match", &etag)]);
+        let response = build_response(&revalidate, &config, &LocalFs);
+        assert_eq!(response.status, 304);
+        assert_eq!(response.header("ETag"), Some(etag.as_str()));
+        assert!(response.body.is_empty());
+
+        fs::write(dir.path().join("todo.txt"), "todo").unwrap();
+        let response = build_response(&revalidate, &config, &LocalFs);
+        assert_eq!(response.status, 200);
+        assert_ne!(response.header("ETag"), Some(etag.as_str()));
+        assert!(String::from_utf8_lossy(&response.body).contains("todo.txt"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::tempdir;
-    use std::path::PathBuf;
+    #[test]
+    fn test_autoindex_over_max_entries_is_truncated_or_forbidden() {
+        let dir = tempdir().unwrap();
+        for n in 0..10 {
+            fs::write(dir.path().join(format!("file{}.txt", n)), "x").unwrap();
+        }
+        let config = ServerConfig {
+            autoindex: true,
+            autoindex_max_entries: Some(3),
+            ..test_config(dir.path())
+        };
 
-    /// Helper to run `handle_response` and return the full HTTP response as a String.
-    fn run_handle_response(method: &str, path: &str, static_dir: &std::path::Path) -> String {
-        let mut buffer = Vec::new();
-        let request = HttpRequest {
-            method: method.to_string(),
-            path: path.to_string(),
+        let response = build_response(&request(Method::Get, "/", &[]), &config, &LocalFs);
+        assert_eq!(response.status, 200);
+        let body = String::from_utf8(response.body).unwrap();
+        assert_eq!(body.matches("<li>").count(), 3, "got: {}", body);
+        assert!(body.contains("Only 3 entries are shown"), "got: {}", body);
+
+        let config = ServerConfig {
+            autoindex_max_entries: Some(10),
+            ..config
         };
-        handle_response(&mut buffer, &request, static_dir.to_str().unwrap()).unwrap();
-        String::from_utf8(buffer).unwrap()
+        let response = build_response(&request(Method::Get, "/", &[]), &config, &LocalFs);
+        let body = String::from_utf8(response.body).unwrap();
+        assert_eq!(body.matches("<li>").count(), 10, "got: {}", body);
+        assert!(!body.contains("Only"), "got: {}", body);
+
+        let config = ServerConfig {
+            autoindex_max_entries: Some(3),
+            autoindex_overflow: AutoindexOverflow::Forbid,
+            ..config
+        };
+        let response = build_response(&request(Method::Get, "/", &[]), &config, &LocalFs);
+        assert_eq!(response.status, 403);
     }
 
-    /// Helper to create a basic static directory for testing.
-    fn setup_static_dir() -> tempfile::TempDir {
+    #[test]
+    fn test_directory_without_index_404s_unless_autoindex() {
         let dir = tempdir().unwrap();
-        let static_dir = dir.path().join("static");
-        fs::create_dir_all(&static_dir).unwrap();
+        fs::write(dir.path().join("notes.txt"), "notes").unwrap();
 
-        // Create simple test files
-        fs::write(static_dir.join("index.html"), "<h2>This is the index.html file.</h2>").unwrap();
-        fs::write(static_dir.join("about.html"), "<h2>This is the about.html file.</h2>").unwrap();
-        fs::write(
-            static_dir.join("crow.html"),
-            "<h2>This is the crow.html file.</h2><img src=\"crow.jpeg\">",
-        )
-        .unwrap();
-        fs::write(static_dir.join("crow.jpeg"), b"fakejpegdata").unwrap();
-        fs::write(static_dir.join("404.html"), "This is the 404 file.").unwrap();
-        fs::write(static_dir.join("index.txt"), "plain text file").unwrap();
+        let response = run_handle_response_with("GET", "/", &test_config(dir.path()));
 
-        dir
+        assert!(response.contains("404 NOT FOUND"), "got: {}", response);
     }
 
     #[test]
-    fn test_handle_response_root_path() {
+    fn test_traversal_outside_root_is_403() {
         let dir = setup_static_dir();
         let static_dir = dir.path().join("static");
+        fs::write(dir.path().join("secret"), "top secret").unwrap();
+        let config = test_config(&static_dir);
 
-        let response = run_handle_response("GET", "/", &static_dir);
-        assert!(response.contains("200 OK"), "Expected status line");
-        assert!(response.contains("index.html"), "Should serve index page");
+        for path in ["/../secret", "/..%2fsecret", "/..%2f..%2fetc", "/%2e%2e/secret"] {
+            let response = run_handle_response_with("GET", path, &config);
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}: {}", path, response);
+            assert!(!response.contains("top secret"), "{}: {}", path, response);
+        }
+
+        fs::create_dir_all(static_dir.join("docs/guide")).unwrap();
+        fs::write(static_dir.join("docs/guide/intro.html"), "<p>intro</p>").unwrap();
+        let response = run_handle_response_with("GET", "/docs/guide/intro.html", &config);
+        assert!(response.ends_with("<p>intro</p>"), "got: {}", response);
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_handle_response_index_path() {
+    fn test_symlink_out_of_root_is_403() {
         let dir = setup_static_dir();
         let static_dir = dir.path().join("static");
+        fs::write(dir.path().join("secret"), "top secret").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("secret"), static_dir.join("link")).unwrap();
 
-        let response = run_handle_response("GET", "/index.html", &static_dir);
-        assert!(response.contains("200 OK"), "Expected status line");
-        assert!(response.contains("<h2>This is the index.html file.</h2>"));
+        let response = run_handle_response_with("GET", "/link", &test_config(&static_dir));
+
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "got: {}", response);
     }
 
     #[test]
-    fn test_handle_response_about_path() {
+    fn test_percent_encoded_paths_are_decoded() {
         let dir = setup_static_dir();
         let static_dir = dir.path().join("static");
+        fs::write(static_dir.join("my file.txt"), "spaced out").unwrap();
+        fs::write(static_dir.join("café.html"), "<p>café</p>").unwrap();
+        let config = test_config(&static_dir);
 
-        let response = run_handle_response("GET", "/about.html", &static_dir);
-        assert!(response.contains("200 OK"), "Expected status line");
-        assert!(response.contains("<h2>This is the about.html file.</h2>"));
+        let response = run_handle_response_with("GET", "/my%20file.txt", &config);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.ends_with("spaced out"), "got: {}", response);
+
+        let response = run_handle_response_with("GET", "/caf%C3%A9.html", &config);
+        assert!(response.ends_with("<p>café</p>"), "got: {}", response);
     }
 
     #[test]
-    fn test_handle_response_crow_path() {
+    fn test_malformed_percent_encoding_is_400() {
         let dir = setup_static_dir();
-        let static_dir = dir.path().join("static");
+        let config = test_config(&dir.path().join("static"));
 
-        let response = run_handle_response("GET", "/crow.html", &static_dir);
-        assert!(response.contains("200 OK"), "Expected status line");
-        assert!(response.contains("<h2>This is the crow.html file.</h2>"));
-        assert!(response.contains("<img src=\"crow.jpeg\">"));
+        for path in ["/bad%2", "/bad%zz.html", "/%ff"] {
+            let response = run_handle_response_with("GET", path, &config);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}: {}", path, response);
+        }
     }
 
     #[test]
-    fn test_handle_response_jpeg() {
+    fn test_cache_rules_set_max_age_by_extension() {
         let dir = setup_static_dir();
         let static_dir = dir.path().join("static");
+        fs::write(static_dir.join("app.js"), "console.log(1);").unwrap();
+        let config = ServerConfig {
+            cache_max_age: Some(60),
+            cache_rules: [("html".to_string(), 300), ("js".to_string(), 31536000)].into_iter().collect(),
+            cache_types: Default::default(),
+            ..test_config(&static_dir)
+        };
 
-        let mut buffer = Vec::new();
-        let request = HttpRequest {
-            method: "GET".to_string(),
-            path: "/crow.jpeg".to_string(),
+        let response = run_handle_response_with("GET", "/about.html", &config);
+        assert!(response.contains("Cache-Control: max-age=300\r\n"), "got: {}", response);
+
+        let response = run_handle_response_with("GET", "/app.js", &config);
+        assert!(response.contains("Cache-Control: max-age=31536000\r\n"), "got: {}", response);
+
+        // No rule for .jpeg, so the global value applies
+        let response = run_handle_response_with("GET", "/crow.jpeg", &config);
+        assert!(response.contains("Cache-Control: max-age=60\r\n"), "got: {}", response);
+
+        let config = ServerConfig {
+            cache_types: Default::default(),
+            ..test_config(&static_dir)
         };
-        handle_response(&mut buffer, &request, static_dir.to_str().unwrap()).unwrap();
+        let response = run_handle_response_with("GET", "/about.html", &config);
+        assert!(!response.contains("Cache-Control"), "got: {}", response);
+    }
 
-        let response_text = String::from_utf8_lossy(&buffer);
-        assert!(response_text.contains("200 OK"), "Expected HTTP 200");
-        assert!(
-            response_text.contains("Content-Type: image/jpeg"),
-            "Expected JPEG content type"
-        );
+    #[test]
+    fn test_cache_types_set_cache_control_by_content_type() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::write(static_dir.join("logo.png"), b"fakepngdata").unwrap();
+
+        let response = run_handle_response("GET", "/logo.png", &static_dir);
+        assert!(response.contains("Cache-Control: max-age=86400\r\n"), "got: {}", response);
+
+        let response = run_handle_response("GET", "/about.html", &static_dir);
+        assert!(response.contains("Cache-Control: no-cache\r\n"), "got: {}", response);
+
+        // An exact type beats its category, and extension rules beat both
+        let config = ServerConfig {
+            cache_rules: [("html".to_string(), 300)].into_iter().collect(),
+            cache_types: [("image/*", "max-age=60"), ("image/png", "max-age=604800, immutable")]
+                .into_iter()
+                .map(|(content_type, value)| (content_type.to_string(), value.to_string()))
+                .collect(),
+            ..test_config(&static_dir)
+        };
+        let response = run_handle_response_with("GET", "/logo.png", &config);
+        assert!(response.contains("Cache-Control: max-age=604800, immutable\r\n"), "got: {}", response);
+        let response = run_handle_response_with("GET", "/crow.jpeg", &config);
+        assert!(response.contains("Cache-Control: max-age=60\r\n"), "got: {}", response);
+        let response = run_handle_response_with("GET", "/about.html", &config);
+        assert!(response.contains("Cache-Control: max-age=300\r\n"), "got: {}", response);
     }
 
     #[test]
-    fn test_handle_response_not_found() {
+    fn test_text_content_types_carry_charset() {
         let dir = setup_static_dir();
         let static_dir = dir.path().join("static");
 
-        let response = run_handle_response("GET", "/unknown", &static_dir);
-        assert!(
-            response.contains("404 NOT FOUND"),
-            "Expected HTTP 404 line, got: {}",
-            response
-        );
-        assert!(
-            response.contains("This is the 404 file."),
-            "Expected 404 body, got: {}",
-            response
-        );
+        let response = run_handle_response("GET", "/about.html", &static_dir);
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"), "got: {}", response);
+
+        let response = run_handle_response("GET", "/crow.jpeg", &static_dir);
+        assert!(response.contains("Content-Type: image/jpeg\r\n"), "got: {}", response);
+
+        let config = ServerConfig {
+            charset: "iso-8859-1".to_string(),
+            ..test_config(&static_dir)
+        };
+        let response = run_handle_response_with("GET", "/index.txt", &config);
+        assert!(response.contains("Content-Type: text/plain; charset=iso-8859-1\r\n"), "got: {}", response);
     }
 
     #[test]
-    fn test_handle_response_content_length_correct() {
+    fn test_every_503_carries_retry_after() {
+        let dir = setup_static_dir();
+        let config = ServerConfig {
+            root_status: Some(503),
+            retry_after: 120,
+            ..test_config(&dir.path().join("static"))
+        };
+
+        // Maintenance mode via --root-status
+        let response = run_handle_response_with("GET", "/", &config);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "got: {}", response);
+        assert!(response.contains("Retry-After: 120\r\n"), "got: {}", response);
+
+        // Over capacity and other server-side 503s
+        let response = error_response(503, &config, &LocalFs);
+        assert_eq!(response.header("Retry-After"), Some("120"));
+
+        let response = error_response(500, &config, &LocalFs);
+        assert_eq!(response.header("Retry-After"), None);
+    }
+
+    /// Store whose files all exist but fail to read with `kind`.
+    struct UnreadableStore(io::ErrorKind);
+
+    impl FileStore for UnreadableStore {
+        fn read(&self, _path: &Path) -> io::Result<Vec<u8>> {
+            Err(io::Error::from(self.0))
+        }
+
+        fn metadata(&self, _path: &Path) -> io::Result<FileMeta> {
+            Ok(FileMeta {
+                len: 0,
+                modified: None,
+                is_dir: false,
+            })
+        }
+
+        fn read_dir(&self, _path: &Path) -> io::Result<Vec<String>> {
+            Err(io::Error::from(self.0))
+        }
+
+        fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+            Ok(path.to_path_buf())
+        }
+    }
+
+    #[test]
+    fn test_read_errors_map_to_status() {
+        let request = HttpRequest {
+            path: "/about.html".to_string(),
+            ..Default::default()
+        };
+        let config = test_config(Path::new("/srv/site"));
+
+        for (kind, status) in [
+            (io::ErrorKind::NotFound, 404),
+            (io::ErrorKind::PermissionDenied, 403),
+            (io::ErrorKind::Other, 500),
+        ] {
+            let response = build_response(&request, &config, &UnreadableStore(kind));
+            assert_eq!(response.status, status, "{:?}", kind);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_file_is_403() {
+        use std::os::unix::fs::PermissionsExt;
+
         let dir = setup_static_dir();
         let static_dir = dir.path().join("static");
+        let locked = static_dir.join("locked.html");
+        fs::write(&locked, "secret").unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // Root reads through file modes, so there is nothing to check
+        if fs::read(&locked).is_ok() {
+            return;
+        }
+
+        let response = run_handle_response("GET", "/locked.html", &static_dir);
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "got: {}", response);
+
+        let response = run_handle_response("GET", "/missing.html", &static_dir);
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"), "got: {}", response);
+    }
+
+    #[test]
+    fn test_response_to_bytes() {
+        let mut response = HttpResponse::new(200, "OK", b"hello".to_vec());
+        response.add_header("Content-Type", "text/plain");
+
+        let bytes = response.to_bytes();
 
-        let response = run_handle_response("GET", "/index.html", &static_dir);
-        let len_line = response
-            .lines()
-            .find(|l| l.starts_with("Content-Length"))
-            .unwrap();
-        let len_val: usize = len_line.split(':').nth(1).unwrap().trim().parse().unwrap();
-        let body = response.split("\r\n\r\n").nth(1).unwrap();
         assert_eq!(
-            body.len(),
-            len_val,
-            "Content-Length header should match actual body size"
+            bytes,
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello".to_vec()
         );
     }
 
+    #[test]
+    fn test_check_content_length_accepts_matching_length() {
+        let response = HttpResponse::new(200, "OK", b"hello".to_vec());
+
+        assert!(response.check_content_length(5).is_ok());
+    }
+
+    #[test]
+    fn test_check_content_length_catches_mismatch() {
+        let mut response = HttpResponse::new(200, "OK", b"hello".to_vec());
+        response.add_header("Content-Length", "10");
+
+        let err = response.check_content_length(response.body.len()).unwrap_err();
+
+        assert!(err.contains("declared 10 but wrote 5"), "got: {}", err);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Content-Length mismatch")]
+    fn test_write_to_panics_on_mismatch_in_debug() {
+        let mut response = HttpResponse::new(200, "OK", b"hello".to_vec());
+        response.add_header("Content-Length", "10");
+
+        response.write_to(Vec::new()).unwrap();
+    }
+
     #[test]
     fn test_handle_404_file_exists() {
         let dir = tempdir().unwrap();
@@ -218,24 +3247,31 @@ mod tests {
         let file_path = static_dir.join("404.html");
         fs::write(&file_path, expected_content).unwrap();
 
-        let old_cwd = std::env::current_dir().unwrap();
-        std::env::set_current_dir(dir.path()).unwrap();
-        let result = handle_404();
-        std::env::set_current_dir(old_cwd).unwrap();
+        let result = handle_404(static_dir.to_str().unwrap(), &LocalFs);
 
         assert_eq!(result, expected_content, "Should return contents of 404.html");
     }
 
+    #[test]
+    fn test_missing_file_serves_404_page_from_custom_root() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("public");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("404.html"), "<h1>Lost in public</h1>").unwrap();
+
+        let response = run_handle_response_with("GET", "/nope.html", &test_config(&root));
+
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"), "got: {}", response);
+        assert!(response.ends_with("<h1>Lost in public</h1>"), "got: {}", response);
+    }
+
     #[test]
     fn test_handle_404_file_missing() {
         let dir = tempdir().unwrap();
         let static_dir = dir.path().join("static");
         fs::create_dir_all(&static_dir).unwrap();
 
-        let old_cwd = std::env::current_dir().unwrap();
-        std::env::set_current_dir(dir.path()).unwrap();
-        let result = handle_404();
-        std::env::set_current_dir(old_cwd).unwrap();
+        let result = handle_404(static_dir.to_str().unwrap(), &LocalFs);
 
         assert_eq!(
             result,
@@ -247,12 +3283,13 @@ mod tests {
     #[test]
     fn generates_index_for_root() {
         let request = HttpRequest {
-            method: "GET".to_string(),
+            method: Method::Get,
             path: "/".to_string(),
+            ..Default::default()
         };
         let root = "/tmp/site";
 
-        let result = generate_path(&request, root);
+        let result = generate_path(&request, root, &ServerConfig::default().index_files, &LocalFs);
 
         assert_eq!(result, PathBuf::from("/tmp/site/index.html"));
     }
@@ -260,12 +3297,13 @@ mod tests {
     #[test]
     fn generates_index_for_index_path() {
         let request = HttpRequest {
-            method: "GET".to_string(),
+            method: Method::Get,
             path: "/index".to_string(),
+            ..Default::default()
         };
         let root = "/tmp/site";
 
-        let result = generate_path(&request, root);
+        let result = generate_path(&request, root, &ServerConfig::default().index_files, &LocalFs);
 
         assert_eq!(result, PathBuf::from("/tmp/site/index.html"));
     }
@@ -273,12 +3311,13 @@ mod tests {
     #[test]
     fn generates_path_for_static_asset() {
         let request = HttpRequest {
-            method: "GET".to_string(),
+            method: Method::Get,
             path: "/css/style.css".to_string(),
+            ..Default::default()
         };
         let root = "/tmp/site";
 
-        let result = generate_path(&request, root);
+        let result = generate_path(&request, root, &ServerConfig::default().index_files, &LocalFs);
 
         assert_eq!(result, PathBuf::from("/tmp/site/css/style.css"));
     }
@@ -286,21 +3325,43 @@ mod tests {
     #[test]
     fn trims_leading_slashes() {
         let request = HttpRequest {
-            method: "GET".to_string(),
+            method: Method::Get,
             path: "///images/logo.png".to_string(),
+            ..Default::default()
         };
         let root = "/tmp/site";
 
-        let result = generate_path(&request, root);
+        let result = generate_path(&request, root, &ServerConfig::default().index_files, &LocalFs);
 
         assert_eq!(result, PathBuf::from("/tmp/site/images/logo.png"));
     }
 
+    #[test]
+    fn serves_first_configured_index_that_exists() {
+        let dir = setup_static_dir();
+        let static_dir = dir.path().join("static");
+        fs::remove_file(static_dir.join("index.html")).unwrap();
+        fs::write(static_dir.join("index.htm"), "<p>htm index</p>").unwrap();
+        fs::write(static_dir.join("home.html"), "<p>home</p>").unwrap();
+        let config = ServerConfig {
+            index_files: vec!["index.html".to_string(), "index.htm".to_string(), "home.html".to_string()],
+            ..test_config(&static_dir)
+        };
+
+        let response = run_handle_response_with("GET", "/", &config);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.ends_with("<p>htm index</p>"), "got: {}", response);
+
+        // The default list only knows index.html
+        let response = run_handle_response_with("GET", "/", &test_config(&static_dir));
+        assert!(response.contains("404 NOT FOUND"), "got: {}", response);
+    }
+
     #[test]
     fn test_mime_type_html() {
         let path = Path::new("somedir/somefile.html");
 
-        let content_type = detect_mime_type(&path);
+        let content_type = detect_mime_type(path);
 
         assert_eq!(content_type, "text/html", "Expected html mimetype");
     }
@@ -309,7 +3370,7 @@ mod tests {
     fn test_mime_type_css() {
         let path = Path::new("somedir/somefile.css");
 
-        let content_type = detect_mime_type(&path);
+        let content_type = detect_mime_type(path);
 
         assert_eq!(content_type, "text/css", "Expected css mimetype");
     }
@@ -318,7 +3379,7 @@ mod tests {
     fn test_mime_type_js() {
         let path = Path::new("somedir/somefile.js");
 
-        let content_type = detect_mime_type(&path);
+        let content_type = detect_mime_type(path);
 
         assert_eq!(content_type, "application/javascript", "Expected js mimetype");
     }
@@ -327,7 +3388,7 @@ mod tests {
     fn test_mime_type_png() {
         let path = Path::new("somedir/somefile.png");
 
-        let content_type = detect_mime_type(&path);
+        let content_type = detect_mime_type(path);
 
         assert_eq!(content_type, "image/png", "Expected png mimetype");
     }
@@ -336,7 +3397,7 @@ mod tests {
     fn test_mime_type_jpg() {
         let path = Path::new("somedir/somefile.jpg");
 
-        let content_type = detect_mime_type(&path);
+        let content_type = detect_mime_type(path);
 
         assert_eq!(content_type, "image/jpeg", "Expected jpg mimetype");
     }
@@ -345,7 +3406,7 @@ mod tests {
     fn test_mime_type_jpeg() {
         let path = Path::new("somedir/somefile.jpeg");
 
-        let content_type = detect_mime_type(&path);
+        let content_type = detect_mime_type(path);
 
         assert_eq!(content_type, "image/jpeg", "Expected jpeg mimetype");
     }
@@ -354,16 +3415,97 @@ mod tests {
     fn test_mime_type_gif() {
         let path = Path::new("somedir/somefile.gif");
 
-        let content_type = detect_mime_type(&path);
+        let content_type = detect_mime_type(path);
 
         assert_eq!(content_type, "image/gif", "Expected gif mimetype");
     }
 
+    #[test]
+    fn test_mime_type_svg() {
+        let path = Path::new("somedir/somefile.svg");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "image/svg+xml", "Expected svg mimetype");
+    }
+
+    #[test]
+    fn test_mime_type_json() {
+        let path = Path::new("somedir/somefile.json");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "application/json", "Expected json mimetype");
+    }
+
+    #[test]
+    fn test_mime_type_woff2() {
+        let path = Path::new("somedir/somefile.woff2");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "font/woff2", "Expected woff2 mimetype");
+    }
+
+    #[test]
+    fn test_mime_type_pdf() {
+        let path = Path::new("somedir/somefile.pdf");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "application/pdf", "Expected pdf mimetype");
+    }
+
+    #[test]
+    fn test_mime_type_txt() {
+        let path = Path::new("somedir/somefile.txt");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "text/plain", "Expected txt mimetype");
+    }
+
+    #[test]
+    fn test_mime_type_wasm() {
+        let path = Path::new("somedir/somefile.wasm");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "application/wasm", "Expected wasm mimetype");
+    }
+
+    #[test]
+    fn test_mime_type_mp4() {
+        let path = Path::new("somedir/somefile.mp4");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "video/mp4", "Expected mp4 mimetype");
+    }
+
+    #[test]
+    fn test_mime_type_webp() {
+        let path = Path::new("somedir/somefile.webp");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "image/webp", "Expected webp mimetype");
+    }
+
+    #[test]
+    fn test_mime_type_ignores_extension_case() {
+        let path = Path::new("somedir/SOMEFILE.HTML");
+
+        let content_type = detect_mime_type(path);
+
+        assert_eq!(content_type, "text/html", "Expected html mimetype");
+    }
+
     #[test]
     fn test_mime_type_other() {
         let path = Path::new("somedir/somefile.other");
 
-        let content_type = detect_mime_type(&path);
+        let content_type = detect_mime_type(path);
 
         assert_eq!(content_type, "application/octet-stream", "Expected other mimetype");
     }
@@ -1,57 +1,670 @@
 // src/server.rs
-use std::net::{TcpListener, TcpStream};
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
-use log::{info, error};
+use anyhow::{Context, Result};
+use log::{info, error, warn};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
 
-use crate::request::{read_request, parse_request};
-use crate::response::handle_response;
+use crate::config::ServerConfig;
+use crate::logger::ACCESS_TARGET;
+use crate::privileges::drop_privileges;
+use crate::ratelimit::RateLimiter;
+use crate::request::{error_status, parse_request_with_limit, read_body, read_request_limited, HttpRequest};
+use crate::response::{add_common_headers, build_response, error_response, warm_up, write_interim, HttpResponse};
+use crate::storage::{FileStore, LimitedStore, LocalFs};
+use crate::trace::TraceParent;
 use crate::threadpool::ThreadPool;
 
 pub fn start_server(port: u16, root: &str) -> Result<()> {
-    let address = format!("127.0.0.1:{}", port);
-    let listener = TcpListener::bind(&address)?;
+    start_server_with_config(ServerConfig {
+        port,
+        root: root.to_string(),
+        ..ServerConfig::default()
+    })
+}
+
+pub fn start_server_with_config(config: ServerConfig) -> Result<()> {
+    let (listener, config, store) = prepare(config)?;
+    serve(listener, config, store, &AtomicBool::new(false))
+}
+
+/// A server running on its own thread, returned by
+/// [`start_server_with_handle`].
+pub struct ServerHandle {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stops accepting connections and waits for connections in flight to
+    /// finish, then frees the port. Kept-alive connections are served until
+    /// the client closes them or the keep-alive timeout passes.
+    pub fn shutdown(self) -> Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        // Unblock accept() so the loop sees the flag
+        let mut wake = self.address;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(wake);
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("server thread panicked"),
+        }
+    }
+}
+
+/// Like [`start_server_with_config`], but binds before returning and runs
+/// the accept loop on a background thread that [`ServerHandle::shutdown`]
+/// can stop.
+pub fn start_server_with_handle(config: ServerConfig) -> Result<ServerHandle> {
+    let (listener, config, store) = prepare(config)?;
+    let address = listener.local_addr().context("Failed to read bound address")?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || serve(listener, config, store, &stop))
+    };
+    Ok(ServerHandle { address, stop, thread })
+}
+
+/// Binds the listener and sets up everything the accept loop needs.
+fn prepare(mut config: ServerConfig) -> Result<(TcpListener, Arc<ServerConfig>, Arc<dyn FileStore>)> {
+    let listener = match &config.port_range {
+        Some(range) => bind_port_range(&config.host, range.clone())?,
+        None => {
+            let address = listen_address(&config.host, config.port)?;
+            bind_with_retries(address, config.bind_retries, config.bind_retry_delay)?
+        }
+    };
+    let address = listener.local_addr().context("Failed to read bound address")?;
+    config.port = address.port();
     info!("Listening on {}", address);
+    drop_privileges(config.user.as_deref(), config.group.as_deref())?;
+    log_startup_summary(&config);
+    let config = Arc::new(config);
+    let store: Arc<dyn FileStore> = match config.max_open_files {
+        Some(max_open) => Arc::new(LimitedStore::new(LocalFs, max_open)),
+        None => Arc::new(LocalFs),
+    };
+    if config.warmup {
+        warm_up(&config, store.as_ref());
+    }
+    Ok((listener, config, store))
+}
 
-    let pool = ThreadPool::new(8);
+/// Accepts connections until `stop` is set, then drains the pool.
+fn serve(listener: TcpListener, config: Arc<ServerConfig>, store: Arc<dyn FileStore>, stop: &AtomicBool) -> Result<()> {
+    let pool = ThreadPool::new_bounded(config.threads, config.queue_capacity);
+    let limiter = config.rate_limit.map(|rate| Arc::new(RateLimiter::new(rate)));
 
     for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
         match stream {
             Ok(stream) => {
-                let root = root.to_string();
-                pool.execute(move || {
-                    if let Err(e) = handle_connection(stream, &root) {
-                        error!("Connection error: {}", e);
+                if let Err(e) = configure_stream(&stream, &config) {
+                    warn!("Failed to set socket options: {}", e);
+                }
+                // Kept so the client can still be answered if the pool is full
+                let overflow = stream.try_clone();
+                let job = {
+                    let config = Arc::clone(&config);
+                    let store = Arc::clone(&store);
+                    let limiter = limiter.clone();
+                    move || {
+                        if let Err(e) = handle_connection(stream, &config, store.as_ref(), limiter.as_deref()) {
+                            error!("Connection error: {}", e);
+                        }
+                    }
+                };
+                if let Err(e) = pool.execute(job) {
+                    warn!("Turning away connection: {}", e);
+                    match overflow {
+                        Ok(stream) => reject_overloaded(&stream, &config, store.as_ref()),
+                        Err(e) => error!("Failed to answer rejected connection: {}", e),
                     }
-                });
+                }
             }
             Err(e) => error!("Error accepting connection: {}", e),
         }
     }
 
+    info!("Shutting down, waiting for open connections");
+    drop(listener);
+    pool.shutdown();
     Ok(())
 }
 
-fn handle_connection(mut stream: TcpStream, root: &str) -> std::io::Result<()> {
-    let request_str = read_request(&mut stream)?;
-    info!("request = {}", request_str);
+/// How long the accept loop will block sending a 503 to a turned-away client.
+const OVERLOAD_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
 
-    let request = parse_request(&request_str)?;
-    info!("method = {} path = {}", request.method, request.path);
+/// Answers a connection the pool has no room for with a 503, without
+/// waiting on the client for longer than `OVERLOAD_WRITE_TIMEOUT`.
+fn reject_overloaded(stream: &TcpStream, config: &ServerConfig, store: &dyn FileStore) {
+    let mut response = error_response(503, config, store);
+    add_common_headers(&mut response, config);
+    response.add_header("Connection", "close");
+    let written = stream
+        .set_write_timeout(Some(OVERLOAD_WRITE_TIMEOUT))
+        .and_then(|()| response.write_to(stream))
+        .and_then(|()| stream.shutdown(Shutdown::Write));
+    if let Err(e) = written {
+        warn!("Failed to send 503 to rejected connection: {}", e);
+    }
+}
 
-    handle_response(&mut stream, &request, root)?;
+/// Logs the effective configuration as a single greppable line.
+pub fn log_startup_summary(config: &ServerConfig) {
+    info!("{}", startup_summary(config));
+}
+
+fn startup_summary(config: &ServerConfig) -> String {
+    fn optional<T: std::fmt::Display>(value: Option<T>) -> String {
+        value.map_or_else(|| "off".to_string(), |v| v.to_string())
+    }
 
+    format!(
+        "startup: address={} root={} threads={} tls=off max_open_files={} \
+         tcp_keepalive_secs={} max_headers={} request_id_header={} welcome={} trace_propagation={}",
+        display_address(&config.host, config.port),
+        config.root,
+        config.threads,
+        optional(config.max_open_files),
+        optional(config.tcp_keepalive.map(|d| d.as_secs())),
+        config.max_headers,
+        config.request_id_header,
+        config.welcome,
+        config.trace_propagation,
+    )
+}
+
+/// `host:port`, bracketing a bare IPv6 host so the port stays readable.
+fn display_address(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Applies per-connection socket options from the config.
+fn configure_stream(stream: &TcpStream, config: &ServerConfig) -> io::Result<()> {
+    if let Some(idle) = config.tcp_keepalive {
+        let keepalive = TcpKeepalive::new().with_time(idle).with_interval(idle);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        let socket = SockRef::from(stream);
+        socket.set_recv_buffer_size(size)?;
+        warn_if_clamped("SO_RCVBUF", size, socket.recv_buffer_size()?);
+    }
+    if let Some(size) = config.send_buffer_size {
+        let socket = SockRef::from(stream);
+        socket.set_send_buffer_size(size)?;
+        warn_if_clamped("SO_SNDBUF", size, socket.send_buffer_size()?);
+    }
     Ok(())
 }
 
+/// The kernel may cap buffer sizes (`net.core.rmem_max` and friends on
+/// Linux), silently leaving a smaller buffer than asked for.
+fn warn_if_clamped(option: &str, requested: usize, actual: usize) {
+    if actual < requested {
+        warn!("{} clamped by the OS: requested {} bytes, got {}", option, requested, actual);
+    }
+}
+
+/// Resolves the address to listen on. IPv6 hosts may be bracketed, as in
+/// URLs (`[::1]`).
+fn listen_address(host: &str, port: u16) -> Result<SocketAddr> {
+    let bare = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    (bare, port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .with_context(|| format!("Invalid listen address {}", host))
+}
+
+/// Binds `address`. The IPv6 wildcard `::` is bound dual-stack so it takes
+/// IPv4 connections too, whatever the OS default.
+fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    if !(address.is_ipv6() && address.ip().is_unspecified()) {
+        return TcpListener::bind(address);
+    }
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    // As std does, so a restart can rebind while old connections linger
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Binds `address`, retrying up to `retries` times while it is in use.
+fn bind_with_retries(address: SocketAddr, retries: u32, delay: Duration) -> Result<TcpListener> {
+    let mut attempt = 0;
+    loop {
+        match bind(address) {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempt < retries => {
+                attempt += 1;
+                warn!("{} in use, retrying bind ({}/{})", address, attempt, retries);
+                thread::sleep(delay);
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to bind {}", address));
+            }
+        }
+    }
+}
+
+/// Binds the first free port in `ports`, failing only if all are in use.
+fn bind_port_range(host: &str, ports: RangeInclusive<u16>) -> Result<TcpListener> {
+    for port in ports.clone() {
+        let address = listen_address(host, port)?;
+        match bind(address) {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                info!("{} in use, trying the next port", address);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to bind {}", address)),
+        }
+    }
+    anyhow::bail!("Every port in {}-{} is in use", ports.start(), ports.end())
+}
+
+/// Serves requests on `stream` until the client closes it or asks to, or
+/// the per-connection request limit is reached.
+fn handle_connection(
+    stream: TcpStream,
+    config: &ServerConfig,
+    store: &dyn FileStore,
+    limiter: Option<&RateLimiter>,
+) -> std::io::Result<()> {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "-".to_string());
+    let mut reader = BufReader::new(CountingReader::new(TimedStream::new(&stream)));
+    // Bytes the requests so far account for: headers plus bodies
+    let mut framed = 0;
+    let mut served = 0;
+    loop {
+        if served > 0 {
+            reader.get_mut().inner.deadline = Some(Instant::now() + config.keep_alive_timeout);
+            match reader.fill_buf() {
+                Ok(buffered) if !buffered.is_empty() => {}
+                // A kept-alive client hanging up or going idle is the normal end
+                Ok(_) => return Ok(()),
+                Err(e) if is_idle_close(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        // However slowly it trickles in, a request gets this long in all
+        reader.get_mut().inner.deadline = Some(Instant::now() + config.request_timeout);
+        let (request_str, header_len) = match read_request_limited(&mut reader, config.max_header_bytes) {
+            Ok(read) => read,
+            Err(e) => {
+                let status = if is_timeout(&e) { Some(408) } else { error_status(&e) };
+                if let Some(status) = status {
+                    send_error_and_close(&stream, status, config, store)?;
+                    drain_before_close(&stream);
+                }
+                return Err(e);
+            }
+        };
+        if let Some(limiter) = limiter
+            && let Ok(addr) = stream.peer_addr()
+            && let Err(wait) = limiter.check(addr.ip())
+        {
+            warn!("Rate limiting {}", peer);
+            send_rate_limited(&stream, wait, config, store)?;
+            drain_before_close(&stream);
+            return Ok(());
+        }
+        served += 1;
+        // The body, if any, gets a fresh window of its own
+        reader.get_mut().inner.deadline = Some(Instant::now() + config.request_timeout);
+        let outcome = serve_request(&stream, &mut reader, &request_str, &peer, served, config, store)?;
+        framed += (header_len as u64) + outcome.body_len;
+        debug_assert_eq!(
+            consumed(&reader),
+            framed,
+            "request framing drifted after request {} on {}",
+            served,
+            peer
+        );
+        if !outcome.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Most unread request bytes skipped, and how long to wait for them, after
+/// answering a request that wasn't read in full.
+const MAX_DRAINED_BYTES: u64 = 64 * 1024;
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reads and drops what the client is still sending before the socket is
+/// closed. Closing with unread data makes the kernel reset the connection,
+/// which can discard the error response before the client reads it.
+fn drain_before_close(stream: &TcpStream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    if stream.set_read_timeout(Some(DRAIN_TIMEOUT)).is_ok() {
+        let _ = io::copy(&mut stream.take(MAX_DRAINED_BYTES), &mut io::sink());
+    }
+}
+
+/// The connection's socket, with reads failing once `deadline` passes
+/// rather than each read getting a fresh timeout.
+struct TimedStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl<'a> TimedStream<'a> {
+    fn new(stream: &'a TcpStream) -> TimedStream<'a> {
+        TimedStream { stream, deadline: None }
+    }
+}
+
+impl Read for TimedStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "read deadline passed"));
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+/// Counts the bytes read through it, so the keep-alive loop can check that
+/// each request consumed exactly its own bytes.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> CountingReader<R> {
+        CountingReader { inner, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Bytes taken off the socket and handed out of the buffer so far.
+fn consumed<R>(reader: &BufReader<CountingReader<R>>) -> u64 {
+    reader.get_ref().count - reader.buffer().len() as u64
+}
+
+fn is_idle_close(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::UnexpectedEof || is_timeout(e)
+}
+
+/// A read timeout shows up as `WouldBlock` on Unix and `TimedOut` elsewhere.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Writes an error response that closes the connection.
+fn send_error_and_close(stream: &TcpStream, status: u16, config: &ServerConfig, store: &dyn FileStore) -> io::Result<()> {
+    let mut response = error_response(status, config, store);
+    add_common_headers(&mut response, config);
+    response.add_header("Connection", "close");
+    response.write_to(stream)
+}
+
+/// Answers a client over its rate limit with 429, telling it how many
+/// seconds until it may retry, and closes the connection.
+fn send_rate_limited(stream: &TcpStream, wait: Duration, config: &ServerConfig, store: &dyn FileStore) -> io::Result<()> {
+    let mut response = error_response(429, config, store);
+    add_common_headers(&mut response, config);
+    response.add_header("Retry-After", wait.as_secs_f64().ceil().max(1.0).to_string());
+    response.add_header("Connection", "close");
+    response.write_to(stream)
+}
+
+/// What serving one request did to the connection.
+struct Served {
+    /// Whether the connection stays open for the next request.
+    keep_alive: bool,
+    /// Body bytes read past the headers.
+    body_len: u64,
+}
+
+/// Answers one request, reading its body if it has one. `served` counts
+/// this request among those on the connection.
+fn serve_request(
+    stream: &TcpStream,
+    reader: &mut impl BufRead,
+    request_str: &str,
+    peer: &str,
+    served: usize,
+    config: &ServerConfig,
+    store: &dyn FileStore,
+) -> std::io::Result<Served> {
+    let start = Instant::now();
+    info!("request = {}", redact_credentials(request_str));
+
+    let parsed = parse_request_with_limit(request_str, config.max_headers)
+        .and_then(|request| request.validate().map(|()| request))
+        .and_then(|request| request.check_body_size(config.max_body_size).map(|()| request));
+    let mut request = match parsed {
+        Ok(request) => request,
+        Err(e) => {
+            if let Some(status) = error_status(&e) {
+                send_error_and_close(stream, status, config, store)?;
+                drain_before_close(stream);
+            }
+            return Err(e);
+        }
+    };
+    let request_id = request
+        .header(&config.request_id_header)
+        .map(str::to_string)
+        .unwrap_or_else(generate_request_id);
+    info!("[{}] method = {} path = {}", request_id, request.method, request.path);
+    if request.is_h2c_upgrade() {
+        // Declining an upgrade by answering over HTTP/1.1 is spec-compliant
+        info!("[{}] ignoring h2c upgrade request, serving HTTP/1.1", request_id);
+    }
+
+    let trace = request_traceparent(&request, config);
+
+    if request.expects_continue() {
+        write_interim(stream, 100)?;
+    }
+
+    // Reading the body also keeps the next request in frame
+    let body_len = read_body(reader, &mut request)?;
+    let body_read = body_len > 0 || !request.has_body();
+    let keep_alive = request.wants_keep_alive() && body_read && served < config.max_requests_per_connection;
+    let mut response = build_response(&request, config, store);
+    response.add_header(&config.request_id_header, request_id.as_str());
+    if let Some(trace) = &trace {
+        response.add_header("traceparent", trace.to_string());
+    }
+    response.add_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+    response.write_to(stream)?;
+    info!(
+        target: ACCESS_TARGET,
+        "{}",
+        access_log_line(peer, &request, &response, &request_id, trace.as_ref())
+    );
+
+    if let Some(message) = slow_request_warning(&request_id, &request.path, start.elapsed(), config.slow_request_threshold) {
+        warn!("{}", message);
+    }
+
+    Ok(Served { keep_alive, body_len })
+}
+
+/// The server's span of the request's trace, when propagation is enabled.
+fn request_traceparent(request: &HttpRequest, config: &ServerConfig) -> Option<TraceParent> {
+    if !config.trace_propagation {
+        return None;
+    }
+    match request.header("traceparent").and_then(TraceParent::parse) {
+        Some(incoming) => Some(incoming.child()),
+        None if config.generate_traceparent => Some(TraceParent::generate()),
+        None => None,
+    }
+}
+
+fn access_log_line(
+    peer: &str,
+    request: &HttpRequest,
+    response: &HttpResponse,
+    request_id: &str,
+    trace: Option<&TraceParent>,
+) -> String {
+    let query = request.query.as_deref().map(|query| format!("?{}", query)).unwrap_or_default();
+    let mut line = format!(
+        "{} \"{} {}{}\" {} {} [{}]",
+        peer,
+        request.method,
+        request.path,
+        query,
+        response.status,
+        response.body_len(),
+        request_id
+    );
+    if let Some(trace) = trace {
+        line.push_str(&format!(" trace_id={}", trace.trace_id));
+    }
+    line
+}
+
+/// The raw request with `Authorization` values masked, for logging.
+fn redact_credentials(request_str: &str) -> String {
+    request_str
+        .split_inclusive("\r\n")
+        .map(|line| match line.split_once(':') {
+            Some((name, _)) if name.trim().eq_ignore_ascii_case("authorization") => format!("{}: [redacted]\r\n", name),
+            _ => line.to_string(),
+        })
+        .collect()
+}
+
+/// Builds the slow-request warning if `elapsed` exceeds the threshold.
+fn slow_request_warning(request_id: &str, path: &str, elapsed: Duration, threshold: Option<Duration>) -> Option<String> {
+    let threshold = threshold?;
+    if elapsed <= threshold {
+        return None;
+    }
+    Some(format!(
+        "[{}] slow request: path = {} took {} ms (threshold {} ms)",
+        request_id,
+        path,
+        elapsed.as_millis(),
+        threshold.as_millis()
+    ))
+}
+
+/// Generates a process-unique request ID from the current time and a counter.
+fn generate_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}", nanos, count)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::io::{Read, Write};
-    use std::thread;
-    use std::time::Duration;
     use std::net::{TcpListener, TcpStream};
+    use crate::request::Method;
+    use log::{LevelFilter, Log, Metadata, Record};
+    use std::sync::{Mutex, Once};
+
+    /// Keeps every line logged by this test binary, so tests can check what
+    /// reached the logs.
+    struct CapturingLogger(Mutex<Vec<String>>);
 
+    impl Log for CapturingLogger {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURED: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+    /// Installs the capturing logger on first use and returns its lines.
+    fn captured_logs() -> Vec<String> {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURED).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+        CAPTURED.0.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn handle_connection_keeps_credentials_out_of_logs() {
+        captured_logs();
+        let credentials = "Basic bG9nLXRlc3Q6bm90LWluLWxvZ3M=";
+
+        serve_one(&format!(
+            "GET /log-redaction-check HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\nConnection: close\r\n\r\n",
+            credentials
+        ));
+
+        let logs = captured_logs();
+        assert!(
+            logs.iter().any(|line| line.contains("GET /log-redaction-check")),
+            "request was never logged"
+        );
+        assert!(!logs.iter().any(|line| line.contains(credentials)), "credentials were logged");
+    }
+
+    #[test]
+    fn redact_credentials_masks_authorization_only() {
+        let request = "GET / HTTP/1.1\r\nHost: a\r\nauthorization: Basic YWRtaW46czNjcmV0\r\n\r\n";
+
+        assert_eq!(
+            redact_credentials(request),
+            "GET / HTTP/1.1\r\nHost: a\r\nauthorization: [redacted]\r\n\r\n"
+        );
+    }
 
     #[test]
     fn start_server_accepts_and_responds() {
@@ -70,7 +683,7 @@ mod tests {
 
         // Send a minimal HTTP GET request
         stream
-            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
             .expect("Failed to write request");
 
         // Read the response
@@ -96,14 +709,14 @@ mod tests {
         // Spawn the server in a separate thread
         thread::spawn(move || {
             let (stream, _) = listener.accept().unwrap();
-            handle_connection(stream, "static").unwrap();
+            handle_connection(stream, &ServerConfig::default(), &LocalFs, None).unwrap();
         });
 
         // Simulate a client
         let mut client = TcpStream::connect(addr).unwrap();
 
         // Send a simple GET request
-        let request = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
         client.write_all(request.as_bytes()).unwrap();
 
         // Read the server's response
@@ -114,4 +727,482 @@ mod tests {
         assert!(response.starts_with("HTTP/1.1 200 OK"));
         assert!(response.contains("<h1>Welcome to Rusty Server</h1>"));
     }
+
+    #[test]
+    fn bind_with_retries_binds_once_port_is_freed() {
+        // Occupy an ephemeral port, then free it shortly afterwards
+        let occupier = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = occupier.local_addr().unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(occupier);
+        });
+
+        let listener = bind_with_retries(address, 10, Duration::from_millis(50));
+
+        assert!(listener.is_ok(), "Expected bind to succeed on retry");
+    }
+
+    #[test]
+    fn bind_with_retries_fails_without_retries() {
+        let occupier = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = occupier.local_addr().unwrap();
+
+        let result = bind_with_retries(address, 0, Duration::from_millis(50));
+
+        assert!(result.is_err(), "Expected bind to fail while port is occupied");
+    }
+
+    #[test]
+    fn bind_port_range_skips_occupied_ports() {
+        let occupier = TcpListener::bind("127.0.0.1:0").unwrap();
+        let first = occupier.local_addr().unwrap().port();
+        let last = first.saturating_add(5);
+
+        let listener = bind_port_range("127.0.0.1", first..=last).unwrap();
+
+        let chosen = listener.local_addr().unwrap().port();
+        assert!(chosen > first && chosen <= last, "bound {} outside {}-{}", chosen, first, last);
+    }
+
+    #[test]
+    fn listen_address_accepts_ipv4_and_ipv6_forms() {
+        assert_eq!(listen_address("127.0.0.1", 80).unwrap().to_string(), "127.0.0.1:80");
+        assert_eq!(listen_address("::1", 80).unwrap().to_string(), "[::1]:80");
+        assert_eq!(listen_address("[::]", 80).unwrap().to_string(), "[::]:80");
+        assert!(listen_address("not an address", 80).is_err());
+    }
+
+    #[test]
+    fn ipv6_wildcard_binds_dual_stack() {
+        let listener = match bind("[::]:0".parse().unwrap()) {
+            Ok(listener) => listener,
+            // No IPv6 on this host
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+        assert!(TcpStream::connect(("::1", port)).is_ok());
+    }
+
+    #[test]
+    fn bind_port_range_fails_when_all_occupied() {
+        let occupier = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = occupier.local_addr().unwrap().port();
+
+        assert!(bind_port_range("127.0.0.1", port..=port).is_err());
+    }
+
+    /// Helper to serve a single raw request through `handle_connection`.
+    fn serve_one(request: &str) -> String {
+        serve_with(request, ServerConfig::default(), true)
+    }
+
+    /// Like `serve_one`, but tolerates `handle_connection` returning an error.
+    fn serve_one_raw(request: &str) -> String {
+        serve_with(request, ServerConfig::default(), false)
+    }
+
+    fn serve_with(request: &str, config: ServerConfig, expect_ok: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let result = handle_connection(stream, &config, &LocalFs, None);
+            if expect_ok {
+                result.unwrap();
+            }
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn handle_connection_serves_file_ignoring_query_string() {
+        let response = serve_one("GET /index.html?v=3 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(response.contains("<h1>Welcome to Rusty Server</h1>"));
+    }
+
+    #[test]
+    fn handle_connection_requires_host_on_http11() {
+        let response = serve_one_raw("GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "got: {}", response);
+
+        let response = serve_one("GET / HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+    }
+
+    #[test]
+    fn handle_connection_echoes_client_request_id() {
+        let response = serve_one("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nX-Request-Id: client-42\r\n\r\n");
+
+        assert!(
+            response.contains("X-Request-Id: client-42\r\n"),
+            "Expected client request ID to be echoed, got: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn handle_connection_generates_request_id_when_absent() {
+        let response = serve_one("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+
+        let id_line = response
+            .lines()
+            .find(|l| l.starts_with("X-Request-Id:"))
+            .expect("Expected a generated X-Request-Id header");
+        assert!(!id_line["X-Request-Id:".len()..].trim().is_empty());
+    }
+
+    #[test]
+    fn generate_request_id_is_unique() {
+        assert_ne!(generate_request_id(), generate_request_id());
+    }
+
+    #[test]
+    fn slow_request_warning_fires_above_threshold() {
+        let message = slow_request_warning("id-1", "/big.bin", Duration::from_millis(250), Some(Duration::from_millis(100)));
+
+        let message = message.expect("Expected a slow-request warning");
+        assert!(message.contains("slow request"));
+        assert!(message.contains("/big.bin"));
+        assert!(message.contains("250 ms"));
+    }
+
+    #[test]
+    fn slow_request_warning_silent_below_threshold_or_unset() {
+        assert!(slow_request_warning("id-1", "/", Duration::from_millis(50), Some(Duration::from_millis(100))).is_none());
+        assert!(slow_request_warning("id-1", "/", Duration::from_secs(10), None).is_none());
+    }
+
+    #[test]
+    fn handle_connection_rejects_too_many_headers() {
+        let headers: String = (0..200).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect();
+        let request = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+
+        let response = serve_one_raw(&request);
+
+        assert!(
+            response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"),
+            "Expected 431, got: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn handle_connection_propagates_traceparent() {
+        let config = ServerConfig {
+            trace_propagation: true,
+            ..ServerConfig::default()
+        };
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\ntraceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\r\n\r\n";
+
+        let response = serve_with(request, config, true);
+
+        let trace_line = response
+            .lines()
+            .find(|l| l.starts_with("traceparent:"))
+            .expect("Expected a traceparent header");
+        assert!(trace_line.contains("-4bf92f3577b34da6a3ce929d0e0e4736-"), "got: {}", trace_line);
+        assert!(!trace_line.contains("00f067aa0ba902b7"), "Expected a new span id, got: {}", trace_line);
+    }
+
+    #[test]
+    fn handle_connection_omits_traceparent_unless_generating() {
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        let config = ServerConfig {
+            trace_propagation: true,
+            ..ServerConfig::default()
+        };
+        assert!(!serve_with(request, config, true).contains("traceparent:"));
+
+        let config = ServerConfig {
+            trace_propagation: true,
+            generate_traceparent: true,
+            ..ServerConfig::default()
+        };
+        assert!(serve_with(request, config, true).contains("traceparent: 00-"));
+    }
+
+    #[test]
+    fn access_log_line_includes_trace_id() {
+        let request = HttpRequest {
+            method: Method::Get,
+            path: "/index.html".to_string(),
+            ..Default::default()
+        };
+        let response = HttpResponse::new(200, "OK", b"hi".to_vec());
+        let trace = TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+
+        let line = access_log_line("127.0.0.1:5000", &request, &response, "req-1", Some(&trace));
+
+        assert_eq!(
+            line,
+            "127.0.0.1:5000 \"GET /index.html\" 200 2 [req-1] trace_id=4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+    }
+
+    #[test]
+    fn handle_connection_declines_h2c_upgrade() {
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings, close\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n";
+
+        let response = serve_one(request);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+        assert!(!response.contains("Upgrade:"), "got: {}", response);
+        assert!(response.contains("<h1>Welcome to Rusty Server</h1>"));
+    }
+
+    #[test]
+    fn handle_connection_sends_continue_before_final_response() {
+        let request = "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nExpect: 100-continue\r\nContent-Length: 0\r\n\r\n";
+
+        let response = serve_one(request);
+
+        // POST isn't served, but the interim response still comes first
+        assert!(response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 405 "), "got: {}", response);
+    }
+
+    #[test]
+    fn configure_stream_sets_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        configure_stream(&stream, &ServerConfig::default()).unwrap();
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        let config = ServerConfig {
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            ..ServerConfig::default()
+        };
+        configure_stream(&stream, &config).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(30));
+
+        drop(client);
+    }
+
+    #[test]
+    fn configure_stream_sets_buffer_sizes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let config = ServerConfig {
+            recv_buffer_size: Some(64 * 1024),
+            send_buffer_size: Some(32 * 1024),
+            ..ServerConfig::default()
+        };
+        configure_stream(&stream, &config).unwrap();
+
+        // Linux reports double the requested size to account for bookkeeping
+        let socket = SockRef::from(&stream);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 32 * 1024);
+
+        drop(client);
+    }
+
+    #[test]
+    fn startup_summary_lists_key_settings() {
+        let config = ServerConfig {
+            port: 9090,
+            root: "/srv/site".to_string(),
+            max_open_files: Some(64),
+            threads: 3,
+            ..ServerConfig::default()
+        };
+
+        let summary = startup_summary(&config);
+
+        assert!(summary.starts_with("startup: "), "got: {}", summary);
+        assert!(summary.contains("address=127.0.0.1:9090"));
+        assert!(summary.contains("root=/srv/site"));
+        assert!(summary.contains("threads=3"));
+        assert!(summary.contains("tls=off"));
+        assert!(summary.contains("max_open_files=64"));
+        assert!(summary.contains("tcp_keepalive_secs=off"));
+        assert!(!summary.contains('\n'));
+    }
+
+    #[test]
+    fn reject_overloaded_sends_503_with_retry_after() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let config = ServerConfig { retry_after: 7, ..ServerConfig::default() };
+
+        reject_overloaded(&stream, &config, &LocalFs);
+        drop(stream);
+
+        let mut response = String::new();
+        (&client).read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "got: {}", response);
+        assert!(response.contains("Retry-After: 7\r\n"), "got: {}", response);
+        assert!(response.contains("Connection: close\r\n"), "got: {}", response);
+    }
+
+    #[test]
+    fn handle_connection_rejects_smuggling_and_closes() {
+        let request = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+
+        // serve_one_raw reads to EOF, so returning at all means the server closed
+        let response = serve_one_raw(request);
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "got: {}", response);
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "got: {}", response);
+    }
+
+    #[test]
+    fn handle_connection_serves_pipelined_requests_on_one_connection() {
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                       GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        let response = serve_one(request);
+
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2, "got: {}", response);
+        let (first, second) = response.split_at(response.rfind("HTTP/1.1 200 OK").unwrap());
+        assert!(first.contains("Connection: keep-alive\r\n"), "got: {}", first);
+        assert!(second.contains("Connection: close\r\n"), "got: {}", second);
+    }
+
+    #[test]
+    fn handle_connection_keeps_pipelined_bodies_in_frame() {
+        let with_body = |body: &str| {
+            format!(
+                "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        };
+        let request = [
+            with_body("hello"),
+            with_body("GET /index.html HTTP/1.1\r\n\r\n"),
+            with_body(""),
+            with_body(&"x".repeat(10_000)),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n".to_string(),
+        ]
+        .concat();
+
+        // A body misread as a request would surface as a 400 or an extra response
+        let response = serve_one(&request);
+
+        assert_eq!(response.matches("HTTP/1.1 405 ").count(), 4, "got: {}", response);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1, "got: {}", response);
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 5, "got: {}", response);
+    }
+
+    #[test]
+    fn handle_connection_rejects_oversized_body_with_413() {
+        let config = ServerConfig {
+            max_body_size: 16,
+            ..ServerConfig::default()
+        };
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n{}GET / HTTP/1.1\r\n\r\n",
+            "x".repeat(17)
+        );
+
+        let response = serve_with(&request, config, false);
+
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "got: {}", response);
+        assert!(response.contains("Connection: close\r\n"), "got: {}", response);
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "got: {}", response);
+    }
+
+    #[test]
+    fn handle_connection_closes_after_max_requests() {
+        let config = ServerConfig {
+            max_requests_per_connection: 2,
+            ..ServerConfig::default()
+        };
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".repeat(2);
+
+        // Neither request asks to close, so reaching EOF means the limit did
+        let response = serve_with(&request, config, true);
+
+        assert_eq!(response.matches("Connection: keep-alive\r\n").count(), 1, "got: {}", response);
+        assert_eq!(response.matches("Connection: close\r\n").count(), 1, "got: {}", response);
+    }
+
+    #[test]
+    fn handle_connection_closes_http10_by_default() {
+        let response = serve_one("GET / HTTP/1.0\r\n\r\n");
+
+        assert!(response.contains("Connection: close\r\n"), "got: {}", response);
+    }
+
+    #[test]
+    fn handle_connection_times_out_partial_request_with_408() {
+        let config = ServerConfig {
+            request_timeout: Duration::from_millis(200),
+            ..ServerConfig::default()
+        };
+        let start = Instant::now();
+
+        // Headers never finish
+        let response = serve_with("GET / HTTP/1.1\r\nHost: local", config, false);
+
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"), "got: {}", response);
+        assert!(response.contains("Connection: close\r\n"), "got: {}", response);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn handle_connection_times_out_trickled_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            request_timeout: Duration::from_millis(300),
+            ..ServerConfig::default()
+        };
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = handle_connection(stream, &config, &LocalFs, None);
+        });
+
+        // A byte every 50ms never trips a per-read timeout, only the deadline
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+        let start = Instant::now();
+        for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Slow: ".iter().cycle() {
+            // Stop once answered, as writing to a closed socket would reset it
+            if client.peek(&mut [0; 1]).is_ok() || start.elapsed() > Duration::from_secs(5) {
+                break;
+            }
+            client.write_all(&[*byte]).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let mut response = String::new();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"), "got: {}", response);
+    }
+
+    #[test]
+    fn handle_connection_closes_idle_keep_alive_connection() {
+        let config = ServerConfig {
+            keep_alive_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        };
+
+        // The client never closes, so reaching EOF means the server hung up
+        let response = serve_with("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", config, true);
+
+        assert!(response.contains("Connection: keep-alive\r\n"), "got: {}", response);
+    }
 }
@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::SystemTime;
+
+/// What the server needs to know about a stored file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMeta {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub is_dir: bool,
+}
+
+/// A readable, seekable handle on a stored file.
+pub trait FileReader: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> FileReader for T {}
+
+/// A file opened for streaming, with its length at open time.
+pub struct OpenFile {
+    pub reader: Box<dyn FileReader>,
+    pub len: u64,
+}
+
+/// Source of the files the server serves.
+pub trait FileStore: Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Opens `path` to be read incrementally. By default this reads the
+    /// whole file and hands out a cursor over it.
+    fn open(&self, path: &Path) -> io::Result<OpenFile> {
+        let contents = self.read(path)?;
+        Ok(OpenFile {
+            len: contents.len() as u64,
+            reader: Box::new(io::Cursor::new(contents)),
+        })
+    }
+    fn metadata(&self, path: &Path) -> io::Result<FileMeta>;
+    /// Names of the entries directly inside `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>>;
+    /// Like `read_dir`, but at most `limit` names, in no particular order.
+    /// Stores that list lazily stop reading there.
+    fn read_dir_limited(&self, path: &Path, limit: usize) -> io::Result<Vec<String>> {
+        let mut names = self.read_dir(path)?;
+        names.truncate(limit);
+        Ok(names)
+    }
+    /// Absolute form of an existing `path` with `..` and links resolved.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+    /// Replaces `path` with `contents`, creating missing parent directories.
+    /// Stores are read-only unless they override this.
+    fn write(&self, _path: &Path, _contents: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "store is read-only"))
+    }
+    /// Deletes `path`, and everything under it if it's a directory.
+    fn remove(&self, _path: &Path) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "store is read-only"))
+    }
+}
+
+/// Serves files from the local filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalFs;
+
+impl FileStore for LocalFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<OpenFile> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        // Opening a directory succeeds; reading it is what fails
+        if metadata.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, "is a directory"));
+        }
+        Ok(OpenFile {
+            reader: Box::new(file),
+            len: metadata.len(),
+        })
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMeta> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileMeta {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            is_dir: metadata.is_dir(),
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn read_dir_limited(&self, path: &Path, limit: usize) -> io::Result<Vec<String>> {
+        fs::read_dir(path)?
+            .take(limit)
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        if fs::symlink_metadata(path)?.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+}
+
+/// Serves files from an in-memory map of path to contents.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn insert(&mut self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        self.files.insert(path.into(), contents.into());
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files.keys().any(|file| file != path && file.starts_with(path))
+    }
+}
+
+impl FileStore for MemoryStore {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files.get(path).cloned().ok_or_else(not_found)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMeta> {
+        if let Some(contents) = self.files.get(path) {
+            return Ok(FileMeta {
+                len: contents.len() as u64,
+                modified: None,
+                is_dir: false,
+            });
+        }
+        if self.is_dir(path) {
+            return Ok(FileMeta {
+                len: 0,
+                modified: None,
+                is_dir: true,
+            });
+        }
+        Err(not_found())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        if !self.is_dir(path) {
+            return Err(not_found());
+        }
+        let mut names: Vec<String> = self
+            .files
+            .keys()
+            .filter_map(|file| file.strip_prefix(path).ok())
+            .filter_map(|rest| rest.components().next())
+            .map(|first| first.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        // No links in memory, so resolving `.` and `..` lexically is enough
+        let mut resolved = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                other => resolved.push(other),
+            }
+        }
+        self.metadata(&resolved)?;
+        Ok(resolved)
+    }
+}
+
+/// Wraps another store, allowing at most `max_open` file operations at once.
+///
+/// Callers beyond the limit wait for a slot instead of failing, which keeps
+/// concurrent requests from exhausting file descriptors.
+pub struct LimitedStore<S> {
+    inner: S,
+    permits: Arc<Semaphore>,
+}
+
+impl<S: FileStore> LimitedStore<S> {
+    /// # Panics
+    /// Panics if `max_open` is 0.
+    pub fn new(inner: S, max_open: usize) -> LimitedStore<S> {
+        assert!(max_open > 0);
+        LimitedStore {
+            inner,
+            permits: Arc::new(Semaphore::new(max_open)),
+        }
+    }
+}
+
+impl<S: FileStore> FileStore for LimitedStore<S> {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let _permit = Semaphore::acquire(&self.permits);
+        self.inner.read(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<OpenFile> {
+        // The handle stays open until the reader is dropped, so it keeps the permit
+        let permit = Semaphore::acquire(&self.permits);
+        let file = self.inner.open(path)?;
+        Ok(OpenFile {
+            len: file.len,
+            reader: Box::new(PermitReader {
+                inner: file.reader,
+                _permit: permit,
+            }),
+        })
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMeta> {
+        // stat doesn't hold a descriptor open, so it needs no permit
+        self.inner.metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        let _permit = Semaphore::acquire(&self.permits);
+        self.inner.read_dir(path)
+    }
+
+    fn read_dir_limited(&self, path: &Path, limit: usize) -> io::Result<Vec<String>> {
+        let _permit = Semaphore::acquire(&self.permits);
+        self.inner.read_dir_limited(path, limit)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let _permit = Semaphore::acquire(&self.permits);
+        self.inner.write(path, contents)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+}
+
+/// Minimal counting semaphore.
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+struct Permit {
+    semaphore: Arc<Semaphore>,
+}
+
+/// A reader that holds a [`LimitedStore`] permit until dropped.
+struct PermitReader {
+    inner: Box<dyn FileReader>,
+    _permit: Permit,
+}
+
+impl Read for PermitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for PermitReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(semaphore: &Arc<Semaphore>) -> Permit {
+        let mut available = semaphore.available.lock().unwrap();
+        while *available == 0 {
+            available = semaphore.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit {
+            semaphore: Arc::clone(semaphore),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "file not found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_canonicalizes_lexically() {
+        let mut store = MemoryStore::new();
+        store.insert("/site/index.html", "<h1>Home</h1>");
+        store.insert("/secret.txt", "hidden");
+
+        assert_eq!(
+            store.canonicalize(Path::new("/site/./css/../index.html")).unwrap(),
+            PathBuf::from("/site/index.html")
+        );
+        assert_eq!(store.canonicalize(Path::new("/site/../secret.txt")).unwrap(), PathBuf::from("/secret.txt"));
+        assert!(store.canonicalize(Path::new("/site/missing.html")).is_err());
+    }
+
+    #[test]
+    fn memory_store_reads_and_lists() {
+        let mut store = MemoryStore::new();
+        store.insert("/site/index.html", "<h1>Home</h1>");
+        store.insert("/site/css/style.css", "body {}");
+
+        assert_eq!(store.read(Path::new("/site/index.html")).unwrap(), b"<h1>Home</h1>");
+        assert_eq!(store.metadata(Path::new("/site/css/style.css")).unwrap().len, 7);
+        assert!(store.metadata(Path::new("/site/css")).unwrap().is_dir);
+        assert_eq!(store.read_dir(Path::new("/site")).unwrap(), vec!["css", "index.html"]);
+        assert_eq!(
+            store.read(Path::new("/site/missing.html")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    /// Store that records the peak number of concurrent reads.
+    #[derive(Default)]
+    struct SlowStore {
+        in_flight: std::sync::atomic::AtomicUsize,
+        peak: std::sync::atomic::AtomicUsize,
+    }
+
+    impl FileStore for SlowStore {
+        fn read(&self, _path: &Path) -> io::Result<Vec<u8>> {
+            use std::sync::atomic::Ordering;
+
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(b"data".to_vec())
+        }
+
+        fn metadata(&self, _path: &Path) -> io::Result<FileMeta> {
+            Err(not_found())
+        }
+
+        fn read_dir(&self, _path: &Path) -> io::Result<Vec<String>> {
+            Err(not_found())
+        }
+
+        fn canonicalize(&self, _path: &Path) -> io::Result<PathBuf> {
+            Err(not_found())
+        }
+    }
+
+    #[test]
+    fn limited_store_bounds_concurrent_reads() {
+        use std::sync::Arc;
+        use std::sync::atomic::Ordering;
+
+        let store = Arc::new(LimitedStore::new(SlowStore::default(), 2));
+
+        let handles: Vec<_> = (0..10)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                std::thread::spawn(move || store.read(Path::new("/any")))
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().unwrap(), b"data");
+        }
+        assert!(store.inner.peak.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn limited_store_holds_a_permit_while_a_file_is_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        fs::write(&file, "abc").unwrap();
+        let store = LimitedStore::new(LocalFs, 1);
+
+        let mut open = store.open(&file).unwrap();
+        assert_eq!(*store.permits.available.lock().unwrap(), 0);
+
+        let mut contents = String::new();
+        open.reader.read_to_string(&mut contents).unwrap();
+        assert_eq!((contents.as_str(), open.len), ("abc", 3));
+        drop(open);
+        assert_eq!(*store.permits.available.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn local_fs_refuses_to_open_directories() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = LocalFs.open(dir.path()).err().unwrap();
+
+        assert_eq!(err.kind(), io::ErrorKind::IsADirectory);
+    }
+
+    #[test]
+    fn local_fs_reads_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        fs::write(&file, "abc").unwrap();
+
+        let meta = LocalFs.metadata(&file).unwrap();
+
+        assert_eq!(meta.len, 3);
+        assert!(!meta.is_dir);
+        assert!(meta.modified.is_some());
+        assert_eq!(LocalFs.read_dir(dir.path()).unwrap(), vec!["a.txt"]);
+    }
+}
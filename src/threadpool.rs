@@ -1,52 +1,167 @@
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
+use log::{error, warn};
+
+/// Worker count used when the available parallelism can't be determined.
+const FALLBACK_THREADS: usize = 4;
+
+/// Default number of worker threads, one per available CPU.
+///
+/// Falls back to `FALLBACK_THREADS` (with a warning) if the platform can't
+/// report its parallelism.
+pub fn default_threads() -> usize {
+    threads_from_parallelism(thread::available_parallelism())
+}
+
+fn threads_from_parallelism(parallelism: io::Result<NonZeroUsize>) -> usize {
+    match parallelism {
+        Ok(n) => n.get(),
+        Err(e) => {
+            warn!("Could not determine available parallelism ({}), using {} threads", e, FALLBACK_THREADS);
+            FALLBACK_THREADS
+        }
+    }
+}
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>, // Wrap in Option for safe drop
+    sender: Option<JobSender>, // Wrap in Option for safe drop
+    counters: Arc<Counters>,
+}
+
+/// A snapshot of how busy a pool is, from [`ThreadPool::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PoolStats {
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+    /// Jobs running right now.
+    pub active: usize,
+    /// Jobs finished since the pool started, including ones that panicked.
+    pub completed: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    completed: AtomicUsize,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+enum JobSender {
+    Unbounded(mpsc::Sender<Job>),
+    Bounded(mpsc::SyncSender<Job>),
+}
+
+/// Why [`ThreadPool::execute`] didn't take a job.
+#[derive(Debug, PartialEq)]
+pub enum ExecuteError {
+    /// A bounded pool's queue is at capacity.
+    QueueFull,
+    /// The workers are gone, so nothing will run the job.
+    Closed,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => write!(f, "thread pool queue is full"),
+            ExecuteError::Closed => write!(f, "thread pool is shut down"),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
 impl ThreadPool {
     /// Create a new ThreadPool with the given number of threads.
     ///
     /// # Panics
     /// Panics if `size` is 0.
     pub fn new(size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::channel();
+        ThreadPool::with_sender(size, JobSender::Unbounded(sender), receiver)
+    }
+
+    /// Create a ThreadPool whose queue holds at most `capacity` jobs waiting
+    /// for a worker; `execute` fails rather than queueing more.
+    ///
+    /// # Panics
+    /// Panics if `size` is 0.
+    pub fn new_bounded(size: usize, capacity: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        ThreadPool::with_sender(size, JobSender::Bounded(sender), receiver)
+    }
+
+    fn with_sender(size: usize, sender: JobSender, receiver: mpsc::Receiver<Job>) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let counters = Arc::new(Counters::default());
 
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&counters)));
         }
 
         ThreadPool {
             workers,
             sender: Some(sender),
+            counters,
         }
     }
 
     /// Submit a job to be executed by the pool.
-    pub fn execute<F>(&self, f: F)
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
-        if let Some(sender) = &self.sender {
-            sender.send(Box::new(f)).unwrap();
+        let job: Job = Box::new(f);
+        // Counted before sending so a worker can't take it off the queue first
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        let sent = match &self.sender {
+            Some(JobSender::Unbounded(sender)) => sender.send(job).map_err(|_| ExecuteError::Closed),
+            Some(JobSender::Bounded(sender)) => sender.try_send(job).map_err(|e| match e {
+                mpsc::TrySendError::Full(_) => ExecuteError::QueueFull,
+                mpsc::TrySendError::Disconnected(_) => ExecuteError::Closed,
+            }),
+            None => Err(ExecuteError::Closed),
+        };
+        if sent.is_err() {
+            self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        sent
+    }
+
+    /// Current queue depth, running jobs and jobs completed so far.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            queued: self.counters.queued.load(Ordering::SeqCst),
+            active: self.counters.active.load(Ordering::SeqCst),
+            completed: self.counters.completed.load(Ordering::SeqCst),
         }
     }
 }
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
+impl ThreadPool {
+    /// Stops taking jobs and returns once every queued job has run and all
+    /// workers have exited.
+    pub fn shutdown(mut self) {
+        self.close_and_join();
+    }
+
+    fn close_and_join(&mut self) {
         // Take the sender out of the Option to close the channel
         self.sender.take(); // Dropped here => channel closed
 
-        // Join all threads
+        // Join all threads; taking each handle makes a second call a no-op
         for worker in &mut self.workers {
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
@@ -55,6 +170,12 @@ impl Drop for ThreadPool {
     }
 }
 
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.close_and_join();
+    }
+}
+
 struct Worker {
     #[allow(dead_code)]
     id: usize,
@@ -62,11 +183,20 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, counters: Arc<Counters>) -> Worker {
         let thread = thread::spawn(move || loop {
             let message = receiver.lock().unwrap().recv();
             match message {
-                Ok(job) => job(),
+                // A panicking job mustn't take its worker down with it
+                Ok(job) => {
+                    counters.queued.fetch_sub(1, Ordering::SeqCst);
+                    counters.active.fetch_add(1, Ordering::SeqCst);
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        error!("Worker {} recovered from a panicking job: {}", id, panic_message(&*payload));
+                    }
+                    counters.active.fetch_sub(1, Ordering::SeqCst);
+                    counters.completed.fetch_add(1, Ordering::SeqCst);
+                }
                 Err(_) => break, // channel closed => exit thread
             }
         });
@@ -78,6 +208,15 @@ impl Worker {
     }
 }
 
+/// The message a panic was raised with, when it is a string.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<non-string panic>")
+}
+
 #[test]
 fn test_threadpool_executes_jobs() {
     use std::sync::{Arc, Mutex};
@@ -91,9 +230,111 @@ fn test_threadpool_executes_jobs() {
         pool.execute(move || {
             let mut val = c.lock().unwrap();
             *val += 1;
-        });
+        })
+        .unwrap();
     }
 
     std::thread::sleep(Duration::from_millis(100));
     assert_eq!(*counter.lock().unwrap(), 10);
 }
+
+#[test]
+fn test_bounded_pool_rejects_jobs_when_queue_is_full() {
+    use std::sync::mpsc::channel;
+
+    let pool = ThreadPool::new_bounded(1, 1);
+    let (release, blocked) = channel::<()>();
+    let (started, wait_started) = channel::<()>();
+
+    // Occupies the only worker until released
+    pool.execute(move || {
+        started.send(()).unwrap();
+        blocked.recv().unwrap();
+    })
+    .unwrap();
+    wait_started.recv().unwrap();
+
+    // Fills the single queue slot
+    pool.execute(|| {}).unwrap();
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+
+    release.send(()).unwrap();
+}
+
+#[test]
+fn test_worker_survives_a_panicking_job() {
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    let pool = ThreadPool::new(1);
+    let (done, finished) = channel();
+
+    pool.execute(|| panic!("bad request")).unwrap();
+    pool.execute(move || done.send(()).unwrap()).unwrap();
+
+    finished
+        .recv_timeout(Duration::from_secs(5))
+        .expect("the job after a panic should still run");
+}
+
+#[test]
+fn test_stats_track_queued_active_and_completed_jobs() {
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    let pool = ThreadPool::new(1);
+    let (release, blocked) = channel::<()>();
+    let (started, wait_started) = channel::<()>();
+
+    pool.execute(move || {
+        started.send(()).unwrap();
+        blocked.recv().unwrap();
+    })
+    .unwrap();
+    wait_started.recv().unwrap();
+    for _ in 0..3 {
+        pool.execute(|| {}).unwrap();
+    }
+    assert_eq!(pool.stats(), PoolStats { queued: 3, active: 1, completed: 0 });
+
+    release.send(()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pool.stats().completed < 4 {
+        assert!(Instant::now() < deadline, "jobs didn't finish: {:?}", pool.stats());
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(pool.stats(), PoolStats { queued: 0, active: 0, completed: 4 });
+}
+
+#[test]
+fn test_shutdown_runs_every_queued_job() {
+    use std::time::Duration;
+
+    let pool = ThreadPool::new(2);
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..8 {
+        let c = Arc::clone(&counter);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(10));
+            c.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+    pool.shutdown();
+
+    assert_eq!(counter.load(Ordering::SeqCst), 8);
+}
+
+#[test]
+fn test_threads_from_parallelism_uses_reported_value() {
+    let threads = threads_from_parallelism(Ok(NonZeroUsize::new(12).unwrap()));
+    assert_eq!(threads, 12);
+}
+
+#[test]
+fn test_threads_from_parallelism_falls_back_on_error() {
+    let err = io::Error::new(io::ErrorKind::Unsupported, "no parallelism info");
+    let threads = threads_from_parallelism(Err(err));
+    assert_eq!(threads, FALLBACK_THREADS);
+}
@@ -0,0 +1,125 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A W3C Trace Context `traceparent` header value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub parent_id: String,
+    pub flags: u8,
+}
+
+impl TraceParent {
+    /// Parses a version-00 `traceparent` value, rejecting all-zero IDs.
+    pub fn parse(value: &str) -> Option<TraceParent> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() != 4 || parts[0] != "00" {
+            return None;
+        }
+        let (trace_id, parent_id, flags) = (parts[1], parts[2], parts[3]);
+        if !is_lower_hex(trace_id, 32) || !is_lower_hex(parent_id, 16) || !is_lower_hex(flags, 2) {
+            return None;
+        }
+        if is_all_zero(trace_id) || is_all_zero(parent_id) {
+            return None;
+        }
+
+        Some(TraceParent {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        })
+    }
+
+    /// Starts a new sampled trace.
+    pub fn generate() -> TraceParent {
+        TraceParent {
+            trace_id: format!("{:016x}{:016x}", random_nonzero_u64(), random_nonzero_u64()),
+            parent_id: format!("{:016x}", random_nonzero_u64()),
+            flags: 1,
+        }
+    }
+
+    /// Continues this trace with a new span for the server's side of the request.
+    pub fn child(&self) -> TraceParent {
+        TraceParent {
+            trace_id: self.trace_id.clone(),
+            parent_id: format!("{:016x}", random_nonzero_u64()),
+            flags: self.flags,
+        }
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{}-{}-{:02x}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+fn is_lower_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn is_all_zero(value: &str) -> bool {
+    value.bytes().all(|b| b == b'0')
+}
+
+fn random_nonzero_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // RandomState is seeded randomly per instance, which is plenty for span IDs
+    loop {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        let value = hasher.finish();
+        if value != 0 {
+            return value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+        let parsed = TraceParent::parse(value).unwrap();
+
+        assert_eq!(parsed.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parsed.parent_id, "00f067aa0ba902b7");
+        assert_eq!(parsed.flags, 1);
+        assert_eq!(parsed.to_string(), value);
+    }
+
+    #[test]
+    fn rejects_invalid_traceparent() {
+        assert!(TraceParent::parse("garbage").is_none());
+        assert!(TraceParent::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(TraceParent::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(TraceParent::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+        assert!(TraceParent::parse("00-4bf92f3577b34da6-00f067aa0ba902b7-01").is_none());
+    }
+
+    #[test]
+    fn child_keeps_trace_id_with_new_span() {
+        let parent = TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+
+        let child = parent.child();
+
+        assert_eq!(child.trace_id, parent.trace_id);
+        assert_ne!(child.parent_id, parent.parent_id);
+        assert!(TraceParent::parse(&child.to_string()).is_some());
+    }
+
+    #[test]
+    fn generated_traceparent_is_valid() {
+        let generated = TraceParent::generate();
+
+        assert!(TraceParent::parse(&generated.to_string()).is_some());
+    }
+}
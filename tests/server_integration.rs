@@ -1,12 +1,13 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
 use std::thread;
 use std::time::Duration;
 use tempfile::tempdir;
 use std::fs;
 
 // Import your server start function
-use rusty_server::server::start_server;
+use rusty_server::server::{start_server, start_server_with_handle, ServerHandle};
+use rusty_server::ServerConfig;
 
 // Helper to start the server on a separate thread
 macro_rules! start_test_server {
@@ -23,6 +24,21 @@ macro_rules! start_test_server {
     }};
 }
 
+// Helper to start a server that tests stop with `handle.shutdown()`
+fn spawn_server(config: ServerConfig) -> ServerHandle {
+    start_server_with_handle(config).expect("failed to start server")
+}
+
+// Helper to send a raw request and read the response until the server closes
+fn get(addr: impl ToSocketAddrs, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).expect("failed to connect to server");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(request.as_bytes()).expect("failed to send request");
+    let mut buffer = String::new();
+    stream.read_to_string(&mut buffer).unwrap();
+    buffer
+}
+
 #[test]
 fn server_responds_to_root_request() {
     // Create a temporary directory and index.html file
@@ -36,7 +52,7 @@ fn server_responds_to_root_request() {
     // Connect as a client
     let mut stream = TcpStream::connect(&addr).expect("failed to connect to server");
     stream
-        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
         .expect("failed to send request");
 
     // Read response
@@ -72,7 +88,7 @@ fn handles_multiple_concurrent_requests() {
             thread::spawn(move || {
                 let mut stream = TcpStream::connect(&addr).unwrap();
                 stream
-                    .write_all(b"GET /test.html HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                    .write_all(b"GET /test.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
                     .unwrap();
 
                 let mut buffer = String::new();
@@ -106,7 +122,7 @@ fn server_returns_404_for_missing_file() {
 
     let mut stream = TcpStream::connect(&addr).expect("failed to connect to server");
     stream
-        .write_all(b"GET /nonexistent.html HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .write_all(b"GET /nonexistent.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
         .expect("failed to send request");
 
     let mut buffer = String::new();
@@ -123,3 +139,246 @@ fn server_returns_404_for_missing_file() {
         buffer
     );
 }
+
+#[test]
+fn serves_concurrent_requests_with_low_open_file_limit() {
+    let tmp_dir = tempdir().unwrap();
+    fs::write(tmp_dir.path().join("big.bin"), vec![b'x'; 256 * 1024]).unwrap();
+
+    let server = spawn_server(ServerConfig {
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        max_open_files: Some(1),
+        ..ServerConfig::default()
+    });
+    let addr = server.local_addr();
+
+    let handles: Vec<_> = (0..16)
+        .map(|_| {
+            thread::spawn(move || {
+                let response = get(addr, "GET /big.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+                assert!(response.starts_with("HTTP/1.1 200 OK"), "got: {}", response);
+                assert!(response.ends_with(&"x".repeat(1024)));
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn serves_with_tcp_keepalive_configured() {
+    let tmp_dir = tempdir().unwrap();
+    fs::write(tmp_dir.path().join("index.html"), "<h1>Keepalive</h1>").unwrap();
+
+    let handle = spawn_server(ServerConfig {
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        tcp_keepalive: Some(Duration::from_secs(15)),
+        ..ServerConfig::default()
+    });
+
+    let buffer = get(handle.local_addr(), "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(buffer.starts_with("HTTP/1.1 200 OK"), "got:\n{}", buffer);
+    assert!(buffer.contains("<h1>Keepalive</h1>"));
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn serves_with_custom_socket_buffer_sizes() {
+    let tmp_dir = tempdir().unwrap();
+    let page = "<p>buffered</p>".repeat(1000);
+    fs::write(tmp_dir.path().join("index.html"), &page).unwrap();
+
+    let handle = spawn_server(ServerConfig {
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        recv_buffer_size: Some(4096),
+        send_buffer_size: Some(4096),
+        ..ServerConfig::default()
+    });
+
+    let buffer = get(handle.local_addr(), "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(buffer.starts_with("HTTP/1.1 200 OK"), "got:\n{}", &buffer[..buffer.len().min(200)]);
+    assert!(buffer.ends_with(&page));
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn shutdown_stops_the_server_and_frees_the_port() {
+    let tmp_dir = tempdir().unwrap();
+    fs::write(tmp_dir.path().join("index.html"), "<h1>Bye</h1>").unwrap();
+
+    let handle = spawn_server(ServerConfig {
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        ..ServerConfig::default()
+    });
+    let addr = handle.local_addr();
+    assert_ne!(addr.port(), 0);
+
+    let buffer = get(addr, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(buffer.contains("<h1>Bye</h1>"));
+
+    handle.shutdown().unwrap();
+
+    assert!(TcpStream::connect(addr).is_err());
+    TcpListener::bind(addr).expect("port should be free after shutdown");
+}
+
+#[test]
+fn idle_connection_does_not_block_other_clients() {
+    let tmp_dir = tempdir().unwrap();
+    fs::write(tmp_dir.path().join("index.html"), "<h1>Pooled</h1>").unwrap();
+
+    let handle = spawn_server(ServerConfig {
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        threads: 2,
+        ..ServerConfig::default()
+    });
+
+    let addr = handle.local_addr();
+
+    // Ties up one worker waiting for a request that never comes
+    let idle = TcpStream::connect(addr).unwrap();
+
+    let clients: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || get(addr, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"))
+        })
+        .collect();
+
+    for client in clients {
+        let buffer = client.join().unwrap();
+        assert!(buffer.contains("<h1>Pooled</h1>"), "got:\n{}", buffer);
+    }
+    drop(idle);
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn serves_on_an_explicit_host() {
+    let tmp_dir = tempdir().unwrap();
+    fs::write(tmp_dir.path().join("index.html"), "<h1>Hosted</h1>").unwrap();
+
+    // Any 127/8 address is loopback, so this proves the host is honoured
+    let handle = spawn_server(ServerConfig {
+        host: "127.0.0.2".to_string(),
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        ..ServerConfig::default()
+    });
+    assert_eq!(handle.local_addr().ip().to_string(), "127.0.0.2");
+
+    let buffer = get(handle.local_addr(), "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(buffer.contains("<h1>Hosted</h1>"), "got:\n{}", buffer);
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn serves_over_ipv6_loopback() {
+    let tmp_dir = tempdir().unwrap();
+    fs::write(tmp_dir.path().join("index.html"), "<h1>Six</h1>").unwrap();
+
+    let handle = match start_server_with_handle(ServerConfig {
+        host: "::1".to_string(),
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        ..ServerConfig::default()
+    }) {
+        Ok(handle) => handle,
+        // No IPv6 on this host
+        Err(_) => return,
+    };
+    let address = handle.local_addr();
+    assert!(address.is_ipv6());
+
+    let buffer = get(address, "GET / HTTP/1.1\r\nHost: [::1]\r\nConnection: close\r\n\r\n");
+    assert!(buffer.contains("<h1>Six</h1>"), "got:\n{}", buffer);
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn garbage_request_line_gets_400() {
+    let tmp_dir = tempdir().unwrap();
+
+    let handle = spawn_server(ServerConfig {
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        ..ServerConfig::default()
+    });
+
+    let buffer = get(handle.local_addr(), "GARBAGE\r\n\r\n");
+    assert!(buffer.starts_with("HTTP/1.1 400 Bad Request\r\n"), "got:\n{}", buffer);
+    assert!(buffer.contains("Connection: close\r\n"), "got:\n{}", buffer);
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn oversized_headers_get_431() {
+    let tmp_dir = tempdir().unwrap();
+
+    let handle = spawn_server(ServerConfig {
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        max_header_bytes: 2048,
+        ..ServerConfig::default()
+    });
+
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\nConnection: close\r\n\r\n",
+        "a".repeat(4096)
+    );
+    let buffer = get(handle.local_addr(), &request);
+    assert!(
+        buffer.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+        "got:\n{}",
+        buffer
+    );
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn hammering_client_gets_429_after_the_limit() {
+    let tmp_dir = tempdir().unwrap();
+    fs::write(tmp_dir.path().join("index.html"), "<h1>Home</h1>").unwrap();
+
+    let handle = spawn_server(ServerConfig {
+        port: 0,
+        root: tmp_dir.path().to_str().unwrap().to_string(),
+        rate_limit: Some(5),
+        ..ServerConfig::default()
+    });
+
+    let statuses: Vec<String> = (0..20)
+        .map(|_| {
+            let buffer = get(handle.local_addr(), "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            assert!(
+                !buffer.starts_with("HTTP/1.1 429") || buffer.contains("\r\nRetry-After: "),
+                "got:\n{}",
+                buffer
+            );
+            buffer.lines().next().unwrap_or_default().to_string()
+        })
+        .collect();
+
+    // The first burst is served; the rest of a fast run is turned away
+    assert!(statuses[..5].iter().all(|status| status == "HTTP/1.1 200 OK"), "{:?}", statuses);
+    assert!(
+        statuses.iter().any(|status| status == "HTTP/1.1 429 Too Many Requests"),
+        "{:?}",
+        statuses
+    );
+
+    handle.shutdown().unwrap();
+}